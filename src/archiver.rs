@@ -0,0 +1,1186 @@
+use crate::auth;
+use crate::checkpoint::{Checkpoint, Checkpoints};
+use crate::condstore;
+use crate::config::{Account, RuleAction, Source};
+use crate::connection::{self, Stream};
+use crate::cutoff;
+use crate::date::{self, UndatedPolicy};
+use crate::flags;
+use crate::header;
+use crate::namespace::{quote, Namespace};
+use crate::plan::Plan;
+use crate::template::{Fields, Template};
+use crate::threads::{self, ThreadFinder, ThreadFolder, Threads};
+use anyhow::{bail, Result};
+use chrono::{DateTime, FixedOffset, Utc};
+use imap::types::{Fetch, Flag, Mailbox, NameAttribute};
+use imap::Session;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::io;
+use std::thread;
+use std::time::{Duration, Instant};
+
+pub type Uid = u32;
+pub type ImapSession = Session<Stream>;
+
+/// Messages to move into each folder, with their dates if they have one
+type FolderMessages = HashMap<String, Vec<(Uid, Option<DateTime<FixedOffset>>)>>;
+
+/// Room in a command line for the tag, the command and short arguments such
+/// as the flags of STORE. Sequence sets, folder names, FETCH items and
+/// search criteria are counted separately.
+const COMMAND_OVERHEAD: usize = 64;
+
+/// A batch that took less than this fraction of the timeout lets the next
+/// one grow
+const FAST_BATCH_FRACTION: u32 = 10;
+
+/// Wait before the first attempt to reconnect, doubled for every further one
+const RETRY_DELAY: Duration = Duration::from_secs(2);
+const MAX_RETRY_DELAY: Duration = Duration::from_secs(60);
+
+///
+/// Length of `start:end`, or of just `start` for a single UID
+///
+fn range_length(start: Uid, end: Uid) -> usize {
+    let digits = |uid: Uid| uid.checked_ilog10().unwrap_or(0) as usize + 1;
+    if start == end {
+        digits(start)
+    } else {
+        digits(start) + 1 + digits(end)
+    }
+}
+
+///
+/// Turn a sorted slice of UIDs into a sequence set, with runs of
+/// consecutive UIDs as ranges, e.g. `1:300,305,310:400`
+///
+fn create_uidset(uids: &[Uid]) -> String {
+    let mut uidset = String::new();
+    let mut rest = uids;
+    while let Some(&start) = rest.first() {
+        let run = rest
+            .windows(2)
+            .take_while(|pair| pair[0].checked_add(1) == Some(pair[1]))
+            .count();
+        let end = rest[run];
+        if !uidset.is_empty() {
+            uidset.push(',');
+        }
+        if start == end {
+            uidset.push_str(&start.to_string());
+        } else {
+            uidset.push_str(&format!("{start}:{end}"));
+        }
+        rest = &rest[run + 1..];
+    }
+    uidset
+}
+
+///
+/// Number of UIDs in the first batch of sorted `uids`: at most
+/// `max_messages`, and few enough that their sequence set stays within
+/// `max_length`. A batch holds at least one UID.
+///
+fn batch_length(uids: &[Uid], max_messages: usize, max_length: usize) -> usize {
+    // Length of the finished runs including their commas, and first UID of
+    // the last run
+    let mut length = 0;
+    let mut run_start = match uids.first() {
+        Some(&uid) => uid,
+        None => return 0,
+    };
+    for (i, &uid) in uids.iter().enumerate().skip(1) {
+        let previous = uids[i - 1];
+        let (next_length, next_run_start) = if previous.checked_add(1) == Some(uid) {
+            (length, run_start)
+        } else {
+            (length + range_length(run_start, previous) + 1, uid)
+        };
+        if i >= max_messages || next_length + range_length(next_run_start, uid) > max_length {
+            return i;
+        }
+        length = next_length;
+        run_start = next_run_start;
+    }
+    uids.len()
+}
+
+///
+/// Split sorted UIDs into batches whose sequence set stays within
+/// `max_length`
+///
+fn batches(uids: &[Uid], max_length: usize) -> Vec<&[Uid]> {
+    let mut batches = Vec::new();
+    let mut rest = uids;
+    while !rest.is_empty() {
+        let (batch, tail) = rest.split_at(batch_length(rest, usize::MAX, max_length));
+        batches.push(batch);
+        rest = tail;
+    }
+    batches
+}
+
+///
+/// Whether an error means that the connection broke, so that a new one may
+/// succeed where it failed
+///
+fn is_transient(error: &anyhow::Error) -> bool {
+    match error.downcast_ref::<imap::Error>() {
+        Some(imap::Error::Io(_) | imap::Error::ConnectionLost) => true,
+        Some(_) => false,
+        None => error.downcast_ref::<io::Error>().is_some(),
+    }
+}
+
+///
+/// Lowercased domain of the first From address of a fetched ENVELOPE
+///
+fn sender_domain(message: &Fetch) -> Option<String> {
+    let from = message.envelope()?.from.as_ref()?;
+    let host = from.first()?.host?;
+    Some(String::from_utf8_lossy(host).to_lowercase())
+}
+
+///
+/// How messages are moved out of the source mailbox
+///
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum MoveMethod {
+    /// UID MOVE (RFC 6851)
+    Move,
+    /// UID COPY, flag as \Deleted and UID EXPUNGE of just those messages
+    /// (RFC 4315)
+    CopyUidExpunge,
+    /// UID COPY, flag as \Deleted and EXPUNGE, which also removes any
+    /// other message already flagged as \Deleted
+    CopyExpunge,
+}
+
+///
+/// A mailbox matched by one of the configured sources
+///
+struct SourceMailbox<'a> {
+    name: String,
+    source: &'a Source,
+    /// Path of the mailbox below the configured source mailbox
+    subfolder: String,
+}
+
+///
+/// What happens to a message, as decided on its own
+///
+#[derive(Debug, PartialEq)]
+enum Verdict {
+    /// Too young, protected or kept by a rule
+    Stay,
+    /// None of the date sources has a date for it
+    Undated,
+    /// Moves to this folder
+    Archive(String, DateTime<FixedOffset>),
+}
+
+///
+/// What was done for one account, printed at the end of the run
+///
+#[derive(Debug, Default)]
+pub struct Summary {
+    pub mailboxes: usize,
+    pub messages_moved: usize,
+    pub folders_created: usize,
+    /// Messages none of the date sources had a date for
+    pub messages_undated: usize,
+    /// Messages left in place by a rule
+    pub messages_kept: usize,
+    /// Messages left in place by each protected flag or keyword
+    pub messages_protected: BTreeMap<String, usize>,
+    /// Messages left in place because another message of their thread
+    /// stays
+    pub messages_held_by_thread: usize,
+    /// Messages already flagged as deleted, left in place when archiving
+    /// without MOVE
+    pub messages_deleted: usize,
+    /// FETCH responses without a UID, which cannot be moved
+    pub messages_without_uid: usize,
+    /// What would have been done, only set on dry runs
+    pub plan: Option<Plan>,
+}
+
+///
+/// An authenticated connection to one account together with the state
+/// that is kept while archiving its mailboxes
+///
+pub struct Archiver<'a> {
+    account: &'a Account,
+    session: ImapSession,
+    namespace: Namespace,
+    existing_folders: HashSet<String>,
+    summary: Summary,
+    dry_run: bool,
+    checkpoints: Option<&'a Checkpoints>,
+    move_method: MoveMethod,
+    /// Server keeps modification sequences (RFC 7162)
+    condstore: bool,
+    /// Messages older than this are archived
+    cutoff: DateTime<Utc>,
+    /// Number of messages in the next batch
+    batch_size: usize,
+    /// Smallest batch the server rejected, which batches never grow to
+    rejected_batch_size: usize,
+    /// Messages of the open mailbox that were copied to the archive but may
+    /// not be expunged yet, so that a batch retried after a reconnect does
+    /// not copy them again
+    copied: HashSet<Uid>,
+}
+
+impl<'a> Archiver<'a> {
+    ///
+    /// Connect and log in to the server of the given account. On a dry run
+    /// mailboxes are opened read-only and nothing is created or moved.
+    /// With `checkpoints` only messages that were not looked at by a
+    /// previous run are searched.
+    ///
+    pub fn connect(
+        account: &'a Account,
+        dry_run: bool,
+        checkpoints: Option<&'a Checkpoints>,
+    ) -> Result<Archiver<'a>> {
+        let mut session = auth::login(connection::connect(account)?, account)?;
+
+        let capabilities = session.capabilities()?;
+        let move_method = if capabilities.has_str("MOVE") {
+            MoveMethod::Move
+        } else if capabilities.has_str("UIDPLUS") {
+            MoveMethod::CopyUidExpunge
+        } else if account.allow_expunge {
+            MoveMethod::CopyExpunge
+        } else {
+            bail!(
+                "Server supports neither MOVE nor UIDPLUS. Set `allow_expunge` or pass \
+                 --allow-expunge to archive with COPY and EXPUNGE, which also removes \
+                 messages that were already marked as deleted."
+            );
+        };
+        let fallback = match move_method {
+            MoveMethod::Move => None,
+            MoveMethod::CopyUidExpunge => Some("UID EXPUNGE"),
+            MoveMethod::CopyExpunge => Some("EXPUNGE"),
+        };
+        if let Some(expunge) = fallback {
+            println!(
+                "[{}] Server does not support MOVE, using COPY and {expunge}",
+                account.name
+            );
+        }
+
+        // QRESYNC implies CONDSTORE. It is not enabled itself: it turns
+        // EXPUNGE responses into VANISHED, which the imap crate cannot parse,
+        // and checkpoints keep no per-message state that vanished UIDs
+        // would have to be removed from.
+        let condstore = capabilities.has_str("CONDSTORE") || capabilities.has_str("QRESYNC");
+
+        let namespace = Namespace::discover(&mut session, account.folder_prefix.as_deref())?;
+        let cutoff = account
+            .cutoff
+            .instant(account.granularity, account.timezone, Utc::now())?;
+        println!(
+            "[{}] Archiving messages older than {}",
+            account.name,
+            cutoff.with_timezone(&account.timezone)
+        );
+
+        Ok(Archiver {
+            account,
+            session,
+            namespace,
+            existing_folders: HashSet::new(),
+            summary: Summary {
+                plan: dry_run.then(Plan::default),
+                ..Summary::default()
+            },
+            dry_run,
+            checkpoints,
+            move_method,
+            condstore,
+            cutoff,
+            batch_size: account.batch_size,
+            rejected_batch_size: usize::MAX,
+            copied: HashSet::new(),
+        })
+    }
+
+    ///
+    /// Archive every configured mailbox and log out
+    ///
+    pub fn run(mut self) -> Result<Summary> {
+        for mailbox in self.expand_sources()? {
+            self.process_mailbox(&mailbox)?;
+        }
+        self.session.logout()?;
+        Ok(self.summary)
+    }
+
+    ///
+    /// List the mailboxes matched by the configured sources. Wildcards and
+    /// recursion never match the archive folders themselves, nor the folder
+    /// undated messages are moved to, and a mailbox matched by several
+    /// sources belongs to the first one.
+    ///
+    fn expand_sources(&mut self) -> Result<Vec<SourceMailbox<'a>>> {
+        let undated_folder = (self.account.undated == UndatedPolicy::Move)
+            .then(|| self.namespace.to_mailbox(&self.account.undated_folder));
+        let archive_roots: Vec<String> = self
+            .account
+            .sources
+            .iter()
+            .map(|source| &source.destination)
+            .chain(
+                self.account
+                    .rules
+                    .iter()
+                    .filter_map(|rule| rule.destination.as_ref()),
+            )
+            .map(|destination| destination.static_prefix())
+            .filter(|prefix| !prefix.is_empty())
+            .map(|prefix| self.namespace.to_mailbox(prefix))
+            .chain(undated_folder)
+            .collect();
+
+        let mut seen = HashSet::new();
+        let mut mailboxes = Vec::new();
+        for source in self.account.sources.iter() {
+            let mut patterns = vec![source.mailbox.clone()];
+            if source.recursive {
+                patterns.extend(self.namespace.children_pattern(&source.mailbox));
+            }
+
+            let mut names = Vec::new();
+            for pattern in patterns.iter() {
+                for name in self.session.list(None, Some(&quote(pattern)))?.iter() {
+                    if !name.attributes().contains(&NameAttribute::NoSelect) {
+                        names.push(name.name().to_string());
+                    }
+                }
+            }
+            names.sort();
+
+            let wildcard = source.mailbox.contains(['*', '%']);
+            if names.is_empty() && !wildcard {
+                bail!("Mailbox {} does not exist", source.mailbox);
+            }
+
+            // Subfolders are relative to the part of the source before any wildcard
+            let root = self.namespace.to_path(&source.mailbox);
+            let root = match root.find(['*', '%']) {
+                Some(end) => root[..end].rfind('/').map_or("", |end| &root[..end]),
+                None => &root,
+            };
+
+            for name in names {
+                let is_archive = archive_roots
+                    .iter()
+                    .any(|archive| self.namespace.contains(archive, &name));
+                if (name != source.mailbox && is_archive) || !seen.insert(name.clone()) {
+                    continue;
+                }
+
+                let path = self.namespace.to_path(&name);
+                let subfolder = path
+                    .strip_prefix(root)
+                    .unwrap_or(&path)
+                    .trim_start_matches('/')
+                    .to_string();
+                mailboxes.push(SourceMailbox {
+                    name,
+                    source,
+                    subfolder,
+                });
+            }
+        }
+        Ok(mailboxes)
+    }
+
+    ///
+    /// Ensure that the given mail folder exists. Checks first if the folder
+    /// already exists and caches the result so that at the most the server
+    /// will see one LIST and one CREATE per folder.
+    ///
+    fn create_folder(&mut self, folder_name: &str) -> Result<()> {
+        if self.existing_folders.contains(folder_name) {
+            // We have already tested/created this folder
+            return Ok(());
+        }
+
+        // Compare the names, as a wildcard in a configured folder name
+        // would make the pattern match other folders
+        let folders = self.session.list(None, Some(&quote(folder_name)))?;
+        let matching = folders
+            .iter()
+            .filter(|folder| folder.name() == folder_name)
+            .count();
+        if matching > 1 {
+            bail!("Server lists folder {folder_name} {matching} times");
+        }
+
+        if matching == 1 {
+            println!(
+                "[{}] Caching existing folder {folder_name}",
+                self.account.name
+            );
+            self.existing_folders.insert(folder_name.to_string());
+            return Ok(());
+        }
+
+        if let Some(plan) = &mut self.summary.plan {
+            println!(
+                "[{}] Would create missing folder {folder_name}",
+                self.account.name
+            );
+            plan.folders_to_create.push(folder_name.to_string());
+        } else {
+            println!(
+                "[{}] Creating missing folder {folder_name}",
+                self.account.name
+            );
+            self.session.create(folder_name)?;
+        }
+        self.existing_folders.insert(folder_name.to_string());
+        self.summary.folders_created += 1;
+        Ok(())
+    }
+
+    ///
+    /// Move given set of messages to the given folder
+    ///
+    fn archive_messages(
+        &mut self,
+        mailbox_name: &str,
+        folder_name: &str,
+        messages: &[(Uid, Option<DateTime<FixedOffset>>)],
+    ) -> Result<()> {
+        if let Some(plan) = &mut self.summary.plan {
+            let dates: Vec<_> = messages.iter().map(|(_, date)| *date).collect();
+            plan.add_move(mailbox_name, folder_name, &dates);
+        } else {
+            let mut uids: Vec<Uid> = messages.iter().map(|(uid, _)| *uid).collect();
+            uids.sort_unstable();
+            // Part of a batch may not be contiguous and need a longer set
+            let max_length = self
+                .account
+                .max_command_length
+                .saturating_sub(COMMAND_OVERHEAD + quote(folder_name).len());
+            if self.move_method == MoveMethod::Move {
+                for batch in batches(&uids, max_length) {
+                    self.session.uid_mv(create_uidset(batch), folder_name)?;
+                }
+            } else {
+                let to_copy: Vec<Uid> = uids
+                    .iter()
+                    .copied()
+                    .filter(|uid| !self.copied.contains(uid))
+                    .collect();
+                for batch in batches(&to_copy, max_length) {
+                    // Unlike uid_mv, uid_copy sends the name as it is
+                    self.session
+                        .uid_copy(create_uidset(batch), quote(folder_name))?;
+                    self.copied.extend(batch);
+                }
+                for batch in batches(&uids, max_length) {
+                    let uidset = create_uidset(batch);
+                    self.session
+                        .uid_store(&uidset, "+FLAGS.SILENT (\\Deleted)")?;
+                    if self.move_method == MoveMethod::CopyUidExpunge {
+                        self.session.uid_expunge(&uidset)?;
+                    } else {
+                        self.session.expunge()?;
+                    }
+                }
+            }
+        }
+        self.summary.messages_moved += messages.len();
+        Ok(())
+    }
+
+    ///
+    /// Index of the first rule each message of `uidset` matches, if any
+    ///
+    fn match_rules(&mut self, uidset: &str) -> Result<HashMap<Uid, usize>> {
+        let mut matches = HashMap::new();
+        for (index, rule) in self.account.rules.iter().enumerate() {
+            for uid in self.session.uid_search(rule.query(uidset))? {
+                matches.entry(uid).or_insert(index);
+            }
+        }
+        Ok(matches)
+    }
+
+    ///
+    /// Add a message to the folder its verdict sends it to
+    ///
+    fn place_message(
+        &mut self,
+        folders: &mut FolderMessages,
+        mailbox: &SourceMailbox,
+        uid: Uid,
+        verdict: Verdict,
+    ) {
+        match verdict {
+            Verdict::Stay => {}
+            Verdict::Undated => {
+                self.summary.messages_undated += 1;
+                if self.account.undated == UndatedPolicy::Move {
+                    let folder = self.namespace.to_mailbox(&self.account.undated_folder);
+                    folders.entry(folder).or_default().push((uid, None));
+                } else {
+                    println!(
+                        "[{}] Message {uid} has no usable date, leaving it in {}",
+                        self.account.name, mailbox.name
+                    );
+                }
+            }
+            Verdict::Archive(folder, date) => {
+                folders.entry(folder).or_default().push((uid, Some(date)));
+            }
+        }
+    }
+
+    ///
+    /// Add the messages of a thread to the folder of its newest or oldest
+    /// message, unless any of them stays. Undated messages go along with
+    /// the rest of their thread.
+    ///
+    fn place_thread(
+        &mut self,
+        folders: &mut FolderMessages,
+        mailbox: &SourceMailbox,
+        members: Vec<(Uid, Verdict)>,
+        thread_folder: ThreadFolder,
+    ) {
+        if members.iter().any(|(_, verdict)| *verdict == Verdict::Stay) {
+            self.summary.messages_held_by_thread += members
+                .iter()
+                .filter(|(_, verdict)| *verdict != Verdict::Stay)
+                .count();
+            return;
+        }
+        let dated = members.iter().filter_map(|(_, verdict)| match verdict {
+            Verdict::Archive(folder, date) => Some((date, folder)),
+            _ => None,
+        });
+        let anchor = match thread_folder {
+            ThreadFolder::Newest => dated.max_by_key(|(date, _)| *date),
+            ThreadFolder::Oldest => dated.min_by_key(|(date, _)| *date),
+        };
+        let Some((_, folder)) = anchor else {
+            for (uid, verdict) in members {
+                self.place_message(folders, mailbox, uid, verdict);
+            }
+            return;
+        };
+        let folder = folder.clone();
+        for (uid, verdict) in members {
+            let date = match verdict {
+                Verdict::Archive(_, date) => Some(date),
+                _ => None,
+            };
+            folders.entry(folder.clone()).or_default().push((uid, date));
+        }
+    }
+
+    ///
+    /// The FETCH items needed to decide where the messages of `mailbox` go
+    ///
+    fn fetch_items(&self, mailbox: &SourceMailbox) -> String {
+        let templates: Vec<&Template> = self
+            .account
+            .rules
+            .iter()
+            .filter_map(|rule| rule.destination.as_ref())
+            .chain([&mailbox.source.destination])
+            .collect();
+        let protected =
+            !self.account.protected_flags.is_empty() || !self.account.protected_keywords.is_empty();
+        let mut items = vec!["UID".to_string(), "INTERNALDATE".to_string()];
+        if protected || self.move_method != MoveMethod::Move {
+            items.push("FLAGS".to_string());
+        }
+        if templates
+            .iter()
+            .any(|template| template.uses_sender_domain())
+        {
+            items.push("ENVELOPE".to_string());
+        }
+        let mut header_names = date::header_names(&self.account.date_sources);
+        if templates.iter().any(|template| template.uses_list_id()) {
+            header_names.push("LIST-ID");
+        }
+        if !header_names.is_empty() {
+            items.push(format!(
+                "BODY.PEEK[HEADER.FIELDS ({})]",
+                header_names.join(" ")
+            ));
+        }
+        format!("({})", items.join(" "))
+    }
+
+    ///
+    /// Decide where each of the sorted `uids` goes, looking at a batch of
+    /// messages at a time with a sequence set of at most `max_length`
+    ///
+    fn judge_messages(
+        &mut self,
+        mailbox: &SourceMailbox,
+        uids: &[Uid],
+        max_length: usize,
+    ) -> Result<Vec<(Uid, Verdict)>> {
+        let mut verdicts = Vec::new();
+        let mut rest = uids;
+        while !rest.is_empty() {
+            let (chunk, tail) = rest.split_at(batch_length(rest, self.batch_size, max_length));
+            let started = Instant::now();
+            let result = self.judge_chunk(mailbox, &create_uidset(chunk));
+            if let Some(judged) = self.adapt_batch_size(chunk.len(), result, started.elapsed())? {
+                verdicts.extend(judged);
+                rest = tail;
+            }
+        }
+        Ok(verdicts)
+    }
+
+    ///
+    /// Decide where each message of `uidset` goes
+    ///
+    fn judge_chunk(
+        &mut self,
+        mailbox: &SourceMailbox,
+        uidset: &str,
+    ) -> Result<Vec<(Uid, Verdict)>> {
+        let rule_matches = self.match_rules(uidset)?;
+        let items = self.fetch_items(mailbox);
+        let messages = self.session.uid_fetch(uidset, items)?;
+
+        let source = self.namespace.to_path(&mailbox.name);
+        let mut verdicts = Vec::new();
+        for message in messages.iter() {
+            // Unsolicited FETCH responses, e.g. about flags changed by
+            // another client, come without one
+            let Some(uid) = message.uid else {
+                self.summary.messages_without_uid += 1;
+                continue;
+            };
+            // Without MOVE, a message flagged as deleted may already have
+            // been copied by a run that broke off before expunging it
+            if self.move_method != MoveMethod::Move
+                && message.flags().contains(&Flag::Deleted)
+                && !self.copied.contains(&uid)
+            {
+                self.summary.messages_deleted += 1;
+                verdicts.push((uid, Verdict::Stay));
+                continue;
+            }
+            if let Some(reason) = flags::protected_by(
+                message.flags(),
+                &self.account.protected_flags,
+                &self.account.protected_keywords,
+            ) {
+                *self.summary.messages_protected.entry(reason).or_default() += 1;
+                verdicts.push((uid, Verdict::Stay));
+                continue;
+            }
+            let rule = rule_matches
+                .get(&uid)
+                .map(|index| &self.account.rules[*index]);
+            if rule.is_some_and(|rule| rule.action == RuleAction::Keep) {
+                self.summary.messages_kept += 1;
+                verdicts.push((uid, Verdict::Stay));
+                continue;
+            }
+
+            let headers = header::unfold(message);
+            let Some(date) = date::message_date(message, &headers, &self.account.date_sources)
+            else {
+                verdicts.push((uid, Verdict::Undated));
+                continue;
+            };
+            if date >= self.cutoff {
+                verdicts.push((uid, Verdict::Stay));
+                continue;
+            }
+            let date = date::in_zone(date, self.account.timezone);
+
+            let destination = rule
+                .and_then(|rule| rule.destination.as_ref())
+                .unwrap_or(&mailbox.source.destination);
+            let sender_domain = sender_domain(message);
+            let list_id = header::list_id(&headers);
+            let fields = Fields {
+                date,
+                source: &source,
+                subfolder: &mailbox.subfolder,
+                sender_domain: sender_domain.as_deref(),
+                list_id: list_id.as_deref(),
+            };
+            let folder = self
+                .namespace
+                .to_mailbox(&destination.render(&fields, self.namespace.delimiter()));
+            verdicts.push((uid, Verdict::Archive(folder, date)));
+        }
+        Ok(verdicts)
+    }
+
+    ///
+    /// Take a batch of messages and archive them. With `threads` the batch
+    /// holds whole threads, which are archived together and may take more
+    /// than one command each to look at.
+    ///
+    fn process_messages(
+        &mut self,
+        mailbox: &SourceMailbox,
+        mut uids: Vec<Uid>,
+        threads: Option<&Threads>,
+        max_length: usize,
+    ) -> Result<()> {
+        println!("[{}] Processing {} messages", self.account.name, uids.len());
+        uids.sort_unstable();
+        let verdicts = self.judge_messages(mailbox, &uids, max_length)?;
+
+        let mut folders = FolderMessages::new();
+        match (threads, self.account.threads) {
+            (Some(threads), Some(thread_folder)) => {
+                let mut members = HashMap::<usize, Vec<(Uid, Verdict)>>::new();
+                for (uid, verdict) in verdicts {
+                    match threads.get(&uid) {
+                        Some(thread) => members.entry(*thread).or_default().push((uid, verdict)),
+                        None => self.place_message(&mut folders, mailbox, uid, verdict),
+                    }
+                }
+                for members in members.into_values() {
+                    self.place_thread(&mut folders, mailbox, members, thread_folder);
+                }
+            }
+            _ => {
+                for (uid, verdict) in verdicts {
+                    self.place_message(&mut folders, mailbox, uid, verdict);
+                }
+            }
+        }
+
+        for (folder, messages) in folders.iter() {
+            self.create_folder(folder)?;
+            self.archive_messages(&mailbox.name, folder, messages)?;
+        }
+
+        Ok(())
+    }
+
+    ///
+    /// Grow the batches after a batch of `count` messages was handled
+    /// quickly, and shrink them when the server rejected it or did not
+    /// respond in time. Returns the result of the batch, or None if a
+    /// rejected batch should be retried with fewer messages. A timeout
+    /// leaves the connection unusable, so it is still an error.
+    ///
+    fn adapt_batch_size<T>(
+        &mut self,
+        count: usize,
+        result: Result<T>,
+        took: Duration,
+    ) -> Result<Option<T>> {
+        let error = match result {
+            Ok(value) => {
+                if count >= self.batch_size && took < self.account.timeout / FAST_BATCH_FRACTION {
+                    self.batch_size = self
+                        .batch_size
+                        .saturating_mul(2)
+                        .min(self.rejected_batch_size - 1)
+                        .max(1);
+                }
+                return Ok(Some(value));
+            }
+            Err(error) => error,
+        };
+
+        let (rejected, timed_out) = match error.downcast_ref::<imap::Error>() {
+            Some(imap::Error::Bad(_)) => (true, false),
+            Some(imap::Error::Io(e)) => (
+                false,
+                matches!(
+                    e.kind(),
+                    io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut
+                ),
+            ),
+            _ => (false, false),
+        };
+        if timed_out {
+            self.batch_size = (count / 2).max(1);
+            return Err(error.context(format!(
+                "No response within {}s to a batch of {count} messages",
+                self.account.timeout.as_secs()
+            )));
+        }
+        if !rejected || count == 1 {
+            return Err(error);
+        }
+
+        self.rejected_batch_size = self.rejected_batch_size.min(count);
+        self.batch_size = count / 2;
+        println!(
+            "[{}] Server rejected a batch of {count} messages, retrying with {}",
+            self.account.name, self.batch_size
+        );
+        Ok(None)
+    }
+
+    ///
+    /// SELECT a mailbox, or EXAMINE it on a dry run
+    ///
+    fn open_mailbox(&mut self, mailbox: &SourceMailbox) -> Result<Mailbox> {
+        let selected = if self.dry_run {
+            self.session.examine(&mailbox.name)?
+        } else {
+            self.session.select(&mailbox.name)?
+        };
+        Ok(selected)
+    }
+
+    ///
+    /// Run `step`, reconnecting and running it again while the connection
+    /// breaks. With `uid_validity` the mailbox is opened again first.
+    ///
+    fn retry<T>(
+        &mut self,
+        mailbox: &SourceMailbox,
+        uid_validity: Option<u32>,
+        mut step: impl FnMut(&mut Self) -> Result<T>,
+    ) -> Result<T> {
+        let mut failures = 0;
+        loop {
+            match step(self) {
+                Ok(value) => return Ok(value),
+                Err(error) => self.recover(mailbox, uid_validity, &mut failures, error)?,
+            }
+        }
+    }
+
+    ///
+    /// Reconnect after the connection broke with `error`, waiting longer
+    /// before each attempt, and open `mailbox` again if its `uid_validity`
+    /// is known. Errors that a new connection does not help with are
+    /// returned, as is the last one once `failures` reaches the configured
+    /// number of retries.
+    ///
+    fn recover(
+        &mut self,
+        mailbox: &SourceMailbox,
+        uid_validity: Option<u32>,
+        failures: &mut u32,
+        mut error: anyhow::Error,
+    ) -> Result<()> {
+        while is_transient(&error) && *failures < self.account.retries {
+            *failures += 1;
+            let delay = RETRY_DELAY
+                .saturating_mul(1 << (*failures - 1).min(16))
+                .min(MAX_RETRY_DELAY);
+            println!(
+                "[{}] {error:#}, reconnecting in {}s ({}/{})",
+                self.account.name,
+                delay.as_secs(),
+                failures,
+                self.account.retries
+            );
+            thread::sleep(delay);
+
+            match self.reopen(mailbox, uid_validity) {
+                Ok(()) => return Ok(()),
+                Err(e) => error = e,
+            }
+        }
+        Err(error)
+    }
+
+    ///
+    /// Log in on a new connection and, with `uid_validity`, open `mailbox`
+    /// again as long as its UIDs still refer to the same messages
+    ///
+    fn reopen(&mut self, mailbox: &SourceMailbox, uid_validity: Option<u32>) -> Result<()> {
+        // The broken session is just dropped, there is nobody to log out from
+        self.session = auth::login(connection::connect(self.account)?, self.account)?;
+        if let Some(uid_validity) = uid_validity {
+            let selected = self.open_mailbox(mailbox)?;
+            if selected.uid_validity != Some(uid_validity) {
+                bail!(
+                    "UIDVALIDITY of {} changed while reconnecting, not resuming",
+                    mailbox.name
+                );
+            }
+        }
+        println!(
+            "[{}] Reconnected, resuming {}",
+            self.account.name, mailbox.name
+        );
+        Ok(())
+    }
+
+    ///
+    /// Remember how far the mailbox has been processed
+    ///
+    fn save_checkpoint(&self, mailbox: &SourceMailbox, checkpoint: Checkpoint) -> Result<()> {
+        match self.checkpoints {
+            Some(checkpoints) if !self.dry_run => {
+                checkpoints.set(&self.account.name, &mailbox.name, checkpoint)
+            }
+            _ => Ok(()),
+        }
+    }
+
+    ///
+    /// Look up the threads of all messages in the selected `mailbox`, a
+    /// batch of headers at a time
+    ///
+    fn find_threads(&mut self, mailbox: &SourceMailbox, uid_validity: u32) -> Result<Threads> {
+        let mut all = self
+            .retry(mailbox, Some(uid_validity), |archiver| {
+                Ok(archiver.session.uid_search("ALL")?)
+            })?
+            .into_iter()
+            .collect::<Vec<Uid>>();
+        all.sort_unstable();
+        let max_length = self
+            .account
+            .max_command_length
+            .saturating_sub(COMMAND_OVERHEAD + threads::FETCH_ITEMS.len());
+        let mut finder = ThreadFinder::default();
+        let mut rest = all.as_slice();
+        while !rest.is_empty() {
+            let (batch, tail) = rest.split_at(batch_length(rest, self.batch_size, max_length));
+            let uidset = create_uidset(batch);
+            self.retry(mailbox, Some(uid_validity), |archiver| {
+                finder.fetch(&mut archiver.session, &uidset)
+            })?;
+            rest = tail;
+        }
+        Ok(finder.finish())
+    }
+
+    ///
+    /// Archive all messages of one source mailbox in batches
+    ///
+    fn process_mailbox(&mut self, mailbox: &SourceMailbox) -> Result<()> {
+        println!("[{}] Archiving mailbox {}", self.account.name, mailbox.name);
+        let checkpoint = self
+            .checkpoints
+            .and_then(|checkpoints| checkpoints.get(&self.account.name, &mailbox.name));
+        self.copied.clear();
+
+        // With CONDSTORE an unchanged mailbox does not even need to be opened
+        let mut highest_modseq = None;
+        if self.condstore && self.checkpoints.is_some() {
+            let status = self.retry(mailbox, None, |archiver| {
+                condstore::status(&mut archiver.session, &mailbox.name)
+            })?;
+            highest_modseq = status.highest_modseq;
+            let unchanged = checkpoint.is_some_and(|checkpoint| {
+                checkpoint.uid_validity == status.uid_validity
+                    && status.uid_next == Some(checkpoint.last_uid.saturating_add(1))
+                    && checkpoint.highest_modseq.is_some()
+                    && checkpoint.highest_modseq == status.highest_modseq
+                    && self.cutoff <= checkpoint.cutoff
+            });
+            if unchanged {
+                println!(
+                    "[{}] Mailbox {} is unchanged since the last run",
+                    self.account.name, mailbox.name
+                );
+                self.summary.mailboxes += 1;
+                return Ok(());
+            }
+        }
+
+        let selected = self.retry(mailbox, None, |archiver| archiver.open_mailbox(mailbox))?;
+        let Some(uid_validity) = selected.uid_validity else {
+            bail!("Server did not report the UIDVALIDITY of {}", mailbox.name);
+        };
+
+        let mut uids = Vec::new();
+        let query = match checkpoint {
+            Some(checkpoint) if checkpoint.uid_validity == uid_validity => {
+                // Messages that changed, e.g. lost a flag that kept them
+                let modseq = checkpoint.highest_modseq.filter(|_| self.condstore);
+                if let Some(modseq) = modseq {
+                    uids = self.retry(mailbox, Some(uid_validity), |archiver| {
+                        condstore::changed_since(&mut archiver.session, checkpoint.last_uid, modseq)
+                    })?;
+                }
+                // New messages and those that were too young last time
+                let mut query = format!(
+                    "OR UID {}:* {}",
+                    checkpoint.last_uid.saturating_add(1),
+                    cutoff::search_since(checkpoint.cutoff, &self.account.date_sources),
+                );
+                // Without CONDSTORE, old messages that are not protected
+                // (any more) are searched again instead
+                let unprotected = flags::search_unprotected(
+                    &self.account.protected_flags,
+                    &self.account.protected_keywords,
+                );
+                if let (None, Some(unprotected), 1..) = (modseq, unprotected, checkpoint.last_uid) {
+                    query = format!("OR ({query}) (UID 1:{} {unprotected})", checkpoint.last_uid);
+                }
+                format!(
+                    "{query} {}",
+                    cutoff::search_before(self.cutoff, &self.account.date_sources)
+                )
+            }
+            Some(_) => {
+                println!(
+                    "[{}] UIDVALIDITY of {} changed, discarding its checkpoint",
+                    self.account.name, mailbox.name
+                );
+                cutoff::search_before(self.cutoff, &self.account.date_sources)
+            }
+            None => cutoff::search_before(self.cutoff, &self.account.date_sources),
+        };
+
+        uids.extend(self.retry(mailbox, Some(uid_validity), |archiver| {
+            Ok(archiver.session.uid_search(&query)?)
+        })?);
+        uids.sort_unstable();
+        uids.dedup();
+
+        // The other messages of a thread decide whether it is archived
+        let mut threads = None;
+        if self.account.threads.is_some() && !uids.is_empty() {
+            let mut found = self.find_threads(mailbox, uid_validity)?;
+            uids = threads::expand(&uids, &mut found);
+            threads = Some(found);
+        }
+
+        // Batches in UID order, so that a checkpoint covers everything below it.
+        // Batches of threads are not, and only the final checkpoint is saved:
+        // messages that stayed with a thread are found again through the
+        // younger or changed messages that kept it.
+        // The modification sequence is the one from before the search, so
+        // that changes made meanwhile are picked up next time.
+        let mut reached = Checkpoint {
+            uid_validity,
+            last_uid: 0,
+            cutoff: self.cutoff,
+            highest_modseq,
+        };
+        // The UID set of a batch is sent along with the FETCH items and the
+        // search of each rule
+        let arguments = self
+            .account
+            .rules
+            .iter()
+            .map(|rule| rule.query("").len())
+            .chain([self.fetch_items(mailbox).len()])
+            .max()
+            .unwrap_or(0);
+        let max_length = self
+            .account
+            .max_command_length
+            .saturating_sub(COMMAND_OVERHEAD + arguments);
+        let mut rest = uids.as_slice();
+        // Reconnections since the last batch that went through
+        let mut failures = 0;
+        while !rest.is_empty() {
+            // Only FETCH and SEARCH are limited to the batch size, a batch
+            // is moved with as few commands as the command length allows
+            let mut length = batch_length(rest, usize::MAX, max_length);
+            if let Some(threads) = &threads {
+                length = threads::batch_end(rest, length, threads);
+            }
+            let (batch, tail) = rest.split_at(length);
+            let result =
+                self.process_messages(mailbox, batch.to_vec(), threads.as_ref(), max_length);
+            if let Err(error) = result {
+                self.recover(mailbox, Some(uid_validity), &mut failures, error)?;
+                continue;
+            }
+            failures = 0;
+            if threads.is_none() {
+                reached.last_uid = *batch.last().unwrap();
+                self.save_checkpoint(mailbox, reached)?;
+            }
+            rest = tail;
+        }
+
+        // Every message that existed when the mailbox was opened has been seen
+        if let Some(uid_next) = selected.uid_next {
+            reached.last_uid = uid_next.saturating_sub(1);
+            self.save_checkpoint(mailbox, reached)?;
+        }
+
+        self.summary.mailboxes += 1;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn range_length_counts_digits() {
+        assert_eq!(range_length(7, 7), 1);
+        assert_eq!(range_length(9, 10), 4);
+        assert_eq!(range_length(1, u32::MAX), 12);
+        assert_eq!(range_length(u32::MAX, u32::MAX), 10);
+    }
+
+    #[test]
+    fn create_uidset_joins_runs() {
+        assert_eq!(create_uidset(&[]), "");
+        assert_eq!(create_uidset(&[42]), "42");
+        assert_eq!(create_uidset(&[1, 2, 3, 4]), "1:4");
+        assert_eq!(create_uidset(&[1, 2, 3, 5, 7, 8]), "1:3,5,7:8");
+        assert_eq!(
+            create_uidset(&[u32::MAX - 1, u32::MAX]),
+            format!("{}:{}", u32::MAX - 1, u32::MAX)
+        );
+    }
+
+    #[test]
+    fn batch_length_matches_uidset() {
+        let uids = [1, 2, 3, 5, 7, 8, 10];
+        for max_length in 1..=create_uidset(&uids).len() + 1 {
+            let length = batch_length(&uids, usize::MAX, max_length);
+            assert!(length >= 1);
+            if length > 1 {
+                assert!(create_uidset(&uids[..length]).len() <= max_length);
+            }
+            if length < uids.len() {
+                assert!(create_uidset(&uids[..length + 1]).len() > max_length);
+            }
+        }
+    }
+
+    #[test]
+    fn batch_length_limits() {
+        assert_eq!(batch_length(&[], 10, 100), 0);
+        // A contiguous run costs the same however long it is
+        let run: Vec<Uid> = (1..=1000).collect();
+        assert_eq!(batch_length(&run, usize::MAX, 8), 1000);
+        assert_eq!(batch_length(&run, 300, 8), 300);
+        // "1,3,5" is exactly 5 long
+        assert_eq!(batch_length(&[1, 3, 5, 7], usize::MAX, 5), 3);
+        assert_eq!(batch_length(&[1, 3, 5, 7], usize::MAX, 4), 2);
+        // A single UID is taken even if it does not fit
+        assert_eq!(batch_length(&[u32::MAX], usize::MAX, 1), 1);
+        assert_eq!(batch_length(&[1, u32::MAX], usize::MAX, 10), 1);
+        assert_eq!(batch_length(&[1, u32::MAX], usize::MAX, 12), 2);
+    }
+
+    #[test]
+    fn batches_cover_all_uids() {
+        let uids: Vec<Uid> = (1..=50).filter(|uid| uid % 3 != 0).collect();
+        let split = batches(&uids, 12);
+        assert_eq!(split.concat(), uids);
+        for batch in split {
+            assert!(create_uidset(batch).len() <= 12);
+        }
+        assert_eq!(batches(&[5], 1), vec![&[5][..]]);
+        assert!(batches(&[], 10).is_empty());
+    }
+}
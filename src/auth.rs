@@ -0,0 +1,302 @@
+use crate::archiver::ImapSession;
+use crate::config::Account;
+use crate::connection::Stream;
+use crate::credentials::{run_command, CredentialSource};
+use anyhow::{bail, Context, Result};
+use clap::ValueEnum;
+use hmac::{Hmac, Mac};
+use imap::{Authenticator, Client};
+use md5::Md5;
+use serde::Deserialize;
+use std::fmt;
+use std::path::PathBuf;
+use std::sync::Arc;
+
+///
+/// How to authenticate to the server
+///
+#[derive(Debug, Clone, Copy, Default, PartialEq, Deserialize, ValueEnum)]
+#[serde(rename_all = "kebab-case")]
+pub enum AuthMethod {
+    /// LOGIN with username and password
+    #[default]
+    Login,
+    /// SASL PLAIN, optionally acting on behalf of `authzid`
+    Plain,
+    /// SASL CRAM-MD5, which does not send the password itself
+    CramMd5,
+    /// SASL XOAUTH2 with an OAuth2 access token (Gmail, Microsoft 365)
+    Xoauth2,
+    /// SASL OAUTHBEARER (RFC 7628) with an OAuth2 access token
+    Oauthbearer,
+}
+
+impl fmt::Display for AuthMethod {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.to_possible_value().unwrap().get_name())
+    }
+}
+
+///
+/// Where an OAuth2 access token comes from
+///
+#[derive(Debug)]
+pub enum TokenSource {
+    /// File containing just the access token
+    File(PathBuf),
+    /// Shell command printing the access token
+    Command(String),
+    /// Exchange a refresh token at the token endpoint of the provider
+    Refresh {
+        token_url: String,
+        client_id: String,
+        client_secret: Option<CredentialSource>,
+        refresh_token: CredentialSource,
+        scope: Option<String>,
+    },
+}
+
+///
+/// Where the secret used to authenticate comes from, matching the
+/// configured `AuthMethod`. Secrets are only read when logging in, so that
+/// a failure only affects its own account.
+///
+#[derive(Debug)]
+pub enum Credentials {
+    Password(CredentialSource),
+    OAuth(TokenSource),
+}
+
+#[derive(Deserialize)]
+struct TokenResponse {
+    access_token: String,
+}
+
+impl TokenSource {
+    ///
+    /// Get a current access token
+    ///
+    fn fetch(&self) -> Result<String> {
+        let token = match self {
+            TokenSource::File(path) => CredentialSource::File(path.clone())
+                .read()
+                .context("Failed to read the OAuth2 token")?,
+            TokenSource::Command(command) => run_command("sh", &["-c", command])?,
+            TokenSource::Refresh {
+                token_url,
+                client_id,
+                client_secret,
+                refresh_token,
+                scope,
+            } => {
+                let refresh_token = refresh_token.read().with_context(|| {
+                    format!("Failed to read the refresh token from {refresh_token}")
+                })?;
+                let client_secret = match client_secret {
+                    Some(source) => Some(source.read().with_context(|| {
+                        format!("Failed to read the client secret from {source}")
+                    })?),
+                    None => None,
+                };
+                let mut form = vec![
+                    ("grant_type", "refresh_token"),
+                    ("refresh_token", refresh_token.as_str()),
+                    ("client_id", client_id.as_str()),
+                ];
+                if let Some(client_secret) = &client_secret {
+                    form.push(("client_secret", client_secret));
+                }
+                if let Some(scope) = scope {
+                    form.push(("scope", scope));
+                }
+
+                let agent = ureq::AgentBuilder::new()
+                    .tls_connector(Arc::new(native_tls::TlsConnector::new()?))
+                    .build();
+                let response = match agent.post(token_url).send_form(&form) {
+                    Ok(response) => response,
+                    Err(ureq::Error::Status(status, response)) => bail!(
+                        "Token endpoint {token_url} returned {status}: {}",
+                        response.into_string().unwrap_or_default()
+                    ),
+                    Err(e) => {
+                        return Err(e)
+                            .context(format!("Failed to reach token endpoint {token_url}"))
+                    }
+                };
+                let response: TokenResponse = response
+                    .into_json()
+                    .with_context(|| format!("Invalid response from token endpoint {token_url}"))?;
+                response.access_token
+            }
+        };
+
+        let token = token.trim();
+        if token.is_empty() {
+            bail!("Empty OAuth2 access token");
+        }
+        Ok(token.to_string())
+    }
+}
+
+///
+/// Initial client response of the OAuth2 SASL mechanisms. An error from
+/// the server arrives as a challenge, which is answered with `abort` so
+/// that the server completes the exchange with NO.
+///
+struct OAuth2 {
+    response: String,
+    abort: &'static str,
+}
+
+impl Authenticator for OAuth2 {
+    type Response = String;
+
+    fn process(&self, challenge: &[u8]) -> String {
+        if challenge.is_empty() {
+            self.response.clone()
+        } else {
+            self.abort.to_string()
+        }
+    }
+}
+
+///
+/// SASL PLAIN (RFC 4616). The authorization identity lets an admin log in
+/// as another user, e.g. with Dovecot master users or Cyrus proxy
+/// authorization.
+///
+struct Plain {
+    authzid: String,
+    authcid: String,
+    password: String,
+}
+
+impl Authenticator for Plain {
+    type Response = String;
+
+    fn process(&self, _challenge: &[u8]) -> String {
+        format!("{}\0{}\0{}", self.authzid, self.authcid, self.password)
+    }
+}
+
+///
+/// SASL CRAM-MD5 (RFC 2195)
+///
+struct CramMd5 {
+    username: String,
+    password: String,
+}
+
+impl Authenticator for CramMd5 {
+    type Response = String;
+
+    fn process(&self, challenge: &[u8]) -> String {
+        let mut mac = Hmac::<Md5>::new_from_slice(self.password.as_bytes())
+            .expect("HMAC takes keys of any length");
+        mac.update(challenge);
+        let digest: String = mac
+            .finalize()
+            .into_bytes()
+            .iter()
+            .map(|byte| format!("{byte:02x}"))
+            .collect();
+        format!("{} {digest}", self.username)
+    }
+}
+
+fn read_password(source: &CredentialSource) -> Result<String> {
+    source
+        .read()
+        .with_context(|| format!("Failed to read the password from {source}"))
+}
+
+///
+/// Authenticate with the configured method
+///
+pub fn login(client: Client<Stream>, account: &Account) -> Result<ImapSession> {
+    let username = account.username.as_str();
+    let session = match (&account.credentials, account.auth) {
+        (Credentials::Password(source), AuthMethod::Login) => client
+            .login(username, read_password(source)?)
+            .map_err(|(e, _)| e),
+        (Credentials::Password(source), AuthMethod::Plain) => {
+            let authenticator = Plain {
+                authzid: account.authzid.clone().unwrap_or_default(),
+                authcid: username.to_string(),
+                password: read_password(source)?,
+            };
+            client
+                .authenticate("PLAIN", &authenticator)
+                .map_err(|(e, _)| e)
+        }
+        (Credentials::Password(source), AuthMethod::CramMd5) => {
+            let authenticator = CramMd5 {
+                username: username.to_string(),
+                password: read_password(source)?,
+            };
+            client
+                .authenticate("CRAM-MD5", &authenticator)
+                .map_err(|(e, _)| e)
+        }
+        (Credentials::OAuth(source), AuthMethod::Xoauth2 | AuthMethod::Oauthbearer) => {
+            let token = source.fetch()?;
+            let (mechanism, authenticator) = if account.auth == AuthMethod::Xoauth2 {
+                let response = format!("user={username}\x01auth=Bearer {token}\x01\x01");
+                (
+                    "XOAUTH2",
+                    OAuth2 {
+                        response,
+                        abort: "",
+                    },
+                )
+            } else {
+                // The GS2 header escapes ',' and '=' in the authorization identity
+                let user = username.replace('=', "=3D").replace(',', "=2C");
+                let response = format!(
+                    "n,a={user},\x01host={}\x01port={}\x01auth=Bearer {token}\x01\x01",
+                    account.server, account.port
+                );
+                (
+                    "OAUTHBEARER",
+                    OAuth2 {
+                        response,
+                        abort: "\x01",
+                    },
+                )
+            };
+            client
+                .authenticate(mechanism, &authenticator)
+                .map_err(|(e, _)| e)
+        }
+        _ => unreachable!("Credentials are resolved for the configured method"),
+    };
+    session.context("Failed IMAP login")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn cram_md5_rfc_2195() {
+        let cram_md5 = CramMd5 {
+            username: "tim".to_string(),
+            password: "tanstaaftanstaaf".to_string(),
+        };
+        assert_eq!(
+            cram_md5.process(b"<1896.697170952@postoffice.reston.mci.net>"),
+            "tim b913a602c7eda7a495b4e6e7334d3890"
+        );
+    }
+
+    #[test]
+    fn plain_with_authorization_identity() {
+        let plain = Plain {
+            authzid: "alice".to_string(),
+            authcid: "admin".to_string(),
+            password: "secret".to_string(),
+        };
+        assert_eq!(plain.process(b""), "alice\0admin\0secret");
+    }
+}
@@ -0,0 +1,123 @@
+use crate::archiver::Uid;
+use anyhow::{Context, Result};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+///
+/// How far a previous run got in one mailbox: every message up to
+/// `last_uid` that is older than `cutoff` has been archived. Younger
+/// messages were kept and have to be looked at again once the cutoff
+/// moves on.
+///
+/// On servers with CONDSTORE this only holds for messages that did not
+/// change after `highest_modseq`.
+///
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct Checkpoint {
+    pub uid_validity: u32,
+    pub last_uid: Uid,
+    pub cutoff: DateTime<Utc>,
+    #[serde(default)]
+    pub highest_modseq: Option<u64>,
+}
+
+type AccountCheckpoints = BTreeMap<String, BTreeMap<String, Checkpoint>>;
+
+///
+/// Checkpoints of every account and mailbox, persisted as JSON so that the
+/// next run only has to look at new messages
+///
+#[derive(Debug)]
+pub struct Checkpoints {
+    path: PathBuf,
+    /// Ignore the stored checkpoints, but still record new ones
+    full_scan: bool,
+    accounts: Mutex<AccountCheckpoints>,
+}
+
+impl Checkpoints {
+    ///
+    /// Read the state file, which does not need to exist yet
+    ///
+    pub fn load(path: &Path, full_scan: bool) -> Result<Checkpoints> {
+        let accounts = match fs::read_to_string(path) {
+            Ok(contents) => serde_json::from_str(&contents)
+                .with_context(|| format!("Invalid state file {}", path.display()))?,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => AccountCheckpoints::new(),
+            Err(e) => return Err(e).with_context(|| format!("Failed to read {}", path.display())),
+        };
+
+        Ok(Checkpoints {
+            path: path.to_path_buf(),
+            full_scan,
+            accounts: Mutex::new(accounts),
+        })
+    }
+
+    pub fn get(&self, account: &str, mailbox: &str) -> Option<Checkpoint> {
+        if self.full_scan {
+            return None;
+        }
+        let accounts = self.accounts.lock().unwrap();
+        accounts.get(account)?.get(mailbox).copied()
+    }
+
+    ///
+    /// Record a checkpoint and write the state file right away, so that an
+    /// interrupted run resumes from here
+    ///
+    pub fn set(&self, account: &str, mailbox: &str, checkpoint: Checkpoint) -> Result<()> {
+        let mut accounts = self.accounts.lock().unwrap();
+        accounts
+            .entry(account.to_string())
+            .or_default()
+            .insert(mailbox.to_string(), checkpoint);
+
+        // Replace the file atomically so that a crash never leaves half of it
+        let temporary = self.path.with_extension("tmp");
+        fs::write(&temporary, serde_json::to_string_pretty(&*accounts)?)
+            .with_context(|| format!("Failed to write {}", temporary.display()))?;
+        fs::rename(&temporary, &self.path)
+            .with_context(|| format!("Failed to replace {}", self.path.display()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+
+    #[test]
+    fn round_trip() {
+        let path = std::env::temp_dir().join(format!(
+            "imap-archive-test-{}-state.json",
+            std::process::id()
+        ));
+        let checkpoint = Checkpoint {
+            uid_validity: 1700000000,
+            last_uid: 42,
+            cutoff: Utc.with_ymd_and_hms(2025, 1, 1, 0, 0, 0).unwrap(),
+            highest_modseq: Some(1234),
+        };
+
+        let checkpoints = Checkpoints::load(&path, false).unwrap();
+        assert_eq!(checkpoints.get("me@example.org", "INBOX"), None);
+        checkpoints
+            .set("me@example.org", "INBOX", checkpoint)
+            .unwrap();
+
+        let reloaded = Checkpoints::load(&path, false).unwrap();
+        assert_eq!(reloaded.get("me@example.org", "INBOX"), Some(checkpoint));
+        assert_eq!(reloaded.get("me@example.org", "Sent"), None);
+        assert_eq!(reloaded.get("other@example.org", "INBOX"), None);
+
+        let full_scan = Checkpoints::load(&path, true).unwrap();
+        assert_eq!(full_scan.get("me@example.org", "INBOX"), None);
+
+        fs::remove_file(&path).unwrap();
+    }
+}
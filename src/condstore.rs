@@ -0,0 +1,110 @@
+use crate::archiver::{ImapSession, Uid};
+use crate::namespace::quote;
+use anyhow::{bail, Result};
+
+///
+/// Find the number following `name` in a response, e.g. the value of
+/// `HIGHESTMODSEQ` in `* STATUS INBOX (UIDNEXT 61 HIGHESTMODSEQ 917)`
+///
+fn response_number(response: &str, name: &str) -> Option<u64> {
+    response
+        .split(|c: char| c.is_whitespace() || "()[]".contains(c))
+        .skip_while(|word| !word.eq_ignore_ascii_case(name))
+        .nth(1)?
+        .parse()
+        .ok()
+}
+
+///
+/// State of a mailbox as reported by STATUS, without selecting it
+///
+#[derive(Debug, Clone, Copy)]
+pub struct MailboxStatus {
+    pub uid_validity: u32,
+    pub uid_next: Option<Uid>,
+    /// None if the mailbox does not keep modification sequences
+    pub highest_modseq: Option<u64>,
+}
+
+///
+/// Ask for the UIDVALIDITY, UIDNEXT and HIGHESTMODSEQ (RFC 7162) of a
+/// mailbox. This also enables CONDSTORE for the session.
+///
+pub fn status(session: &mut ImapSession, mailbox: &str) -> Result<MailboxStatus> {
+    let response = session.run_command_and_read_response(format!(
+        "STATUS {} (UIDVALIDITY UIDNEXT HIGHESTMODSEQ)",
+        quote(mailbox)
+    ))?;
+    let response = String::from_utf8_lossy(&response);
+    let attributes = status_attributes(&response);
+
+    let Some(uid_validity) = response_number(attributes, "UIDVALIDITY") else {
+        bail!("Server did not report the UIDVALIDITY of {mailbox}");
+    };
+    Ok(MailboxStatus {
+        uid_validity: uid_validity.try_into()?,
+        uid_next: response_number(attributes, "UIDNEXT").and_then(|uid| uid.try_into().ok()),
+        highest_modseq: response_number(attributes, "HIGHESTMODSEQ").filter(|modseq| *modseq > 0),
+    })
+}
+
+///
+/// The attribute list of the STATUS response, which comes after the
+/// mailbox name, so that a name like "Old UIDNEXT 5" is not mistaken for
+/// an attribute
+///
+fn status_attributes(response: &str) -> &str {
+    response
+        .lines()
+        .find(|line| line.starts_with("* STATUS "))
+        .and_then(|line| line.rfind('(').map(|start| &line[start..]))
+        .unwrap_or("")
+}
+
+///
+/// UIDs up to `last_uid` of the messages that changed after `modseq`.
+/// This uses the CHANGEDSINCE FETCH modifier instead of the MODSEQ search
+/// key, as the imap crate cannot parse the SEARCH response of the latter.
+///
+pub fn changed_since(session: &mut ImapSession, last_uid: Uid, modseq: u64) -> Result<Vec<Uid>> {
+    if last_uid == 0 {
+        return Ok(Vec::new());
+    }
+    let messages = session.uid_fetch(
+        format!("1:{last_uid}"),
+        format!("(UID) (CHANGEDSINCE {modseq})"),
+    )?;
+    Ok(messages.iter().filter_map(|message| message.uid).collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn status_response() {
+        let response = "* STATUS \"Old UIDNEXT 5 (x)\" (UIDVALIDITY 1700000000 UIDNEXT 61 \
+                        HIGHESTMODSEQ 917)\r\n\
+                        a5 OK Status completed (0.001 + 0.000 secs).\r\n";
+        let attributes = status_attributes(response);
+        assert_eq!(response_number(attributes, "UIDVALIDITY"), Some(1700000000));
+        assert_eq!(response_number(attributes, "UIDNEXT"), Some(61));
+        assert_eq!(response_number(attributes, "highestmodseq"), Some(917));
+
+        let response = "* STATUS INBOX (UIDVALIDITY 3 UIDNEXT 1)\r\na5 OK done\r\n";
+        let attributes = status_attributes(response);
+        assert_eq!(response_number(attributes, "UIDNEXT"), Some(1));
+        assert_eq!(response_number(attributes, "HIGHESTMODSEQ"), None);
+        assert_eq!(status_attributes("a5 OK done\r\n"), "");
+    }
+
+    #[test]
+    fn response_numbers() {
+        assert_eq!(
+            response_number("* OK [HIGHESTMODSEQ 715194045007]", "HIGHESTMODSEQ"),
+            Some(715194045007)
+        );
+        assert_eq!(response_number("(UIDNEXT)", "UIDNEXT"), None);
+        assert_eq!(response_number("(UIDNEXT NIL)", "UIDNEXT"), None);
+    }
+}
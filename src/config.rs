@@ -0,0 +1,1207 @@
+use crate::auth::{AuthMethod, Credentials, TokenSource};
+use crate::connection::{parse_fingerprint, ClientCert, Fingerprint, TlsMode};
+use crate::credentials::{read_env, CredentialSource};
+use crate::cutoff::{Age, Cutoff};
+use crate::date::{DateSource, UndatedPolicy};
+use crate::flags::{self, ProtectedFlag};
+use crate::namespace::quote;
+use crate::template::{Granularity, Template};
+use crate::threads::ThreadFolder;
+use anyhow::{bail, Context, Result};
+use chrono::NaiveDate;
+use chrono_tz::Tz;
+use clap::Parser;
+use serde::de::Error;
+use serde::{Deserialize, Deserializer};
+use std::collections::{BTreeMap, HashSet};
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+/// Configuration file that is used when `--config` is not given
+const DEFAULT_CONFIG_FILE: &str = "imap-archive.toml";
+
+const DEFAULT_MAILBOX: &str = "INBOX";
+const DEFAULT_USERNAME_ENV: &str = "IMAP_USERNAME";
+const DEFAULT_PASSWORD_ENV: &str = "IMAP_PASSWORD";
+const DEFAULT_CONCURRENCY: usize = 1;
+const DEFAULT_UNDATED_FOLDER: &str = "Archives/Undated";
+const DEFAULT_PROTECTED_FLAGS: [ProtectedFlag; 3] = [
+    ProtectedFlag::Flagged,
+    ProtectedFlag::Draft,
+    ProtectedFlag::Unseen,
+];
+const DEFAULT_PROTECTED_KEYWORDS: [&str; 2] = ["$Important", "$Todo"];
+const DEFAULT_BATCH_SIZE: usize = 256;
+const DEFAULT_TIMEOUT: u64 = 120;
+const DEFAULT_RETRIES: u32 = 5;
+/// Longest command line, as RFC 7162 asks clients to respect
+const DEFAULT_MAX_COMMAND_LENGTH: usize = 8192;
+/// Shortest sensible `max_command_length`, which has to fit the tag,
+/// command, folder name and a few UIDs
+const MIN_COMMAND_LENGTH: usize = 1024;
+
+///
+/// Command line flags. Anything given here overrides the configuration file.
+///
+#[derive(Debug, Parser)]
+#[command(
+    version,
+    about = "Move old messages from IMAP mailboxes into archive folders"
+)]
+pub struct Args {
+    /// Configuration file [default: imap-archive.toml if it exists]
+    #[arg(short, long, value_name = "FILE")]
+    pub config: Option<PathBuf>,
+
+    /// Only process the named account, may be repeated
+    #[arg(short, long = "account", value_name = "NAME")]
+    pub accounts: Vec<String>,
+
+    /// Number of accounts processed in parallel
+    #[arg(long)]
+    pub concurrency: Option<usize>,
+
+    /// Only report what would be archived, without changing anything
+    #[arg(short = 'n', long)]
+    pub dry_run: bool,
+
+    /// Write the dry run plan as JSON to this file
+    #[arg(long, value_name = "FILE", requires = "dry_run")]
+    pub plan: Option<PathBuf>,
+
+    /// Remember how far each mailbox was processed in this file, so that
+    /// later runs only look at new messages
+    #[arg(long = "state", value_name = "FILE")]
+    pub state_file: Option<PathBuf>,
+
+    /// Look at every message again, ignoring the state file
+    #[arg(long)]
+    pub full_scan: bool,
+
+    /// IMAP server hostname
+    #[arg(long)]
+    pub server: Option<String>,
+
+    /// IMAP server port [default: 993 with implicit TLS, 143 otherwise]
+    #[arg(long)]
+    pub port: Option<u16>,
+
+    /// Seconds to wait for the server before giving up on the connection
+    /// [default: 120]
+    #[arg(long, value_name = "SECONDS")]
+    pub timeout: Option<u64>,
+
+    /// Number of times to reconnect after the connection broke before
+    /// giving up [default: 5]
+    #[arg(long, value_name = "N")]
+    pub retries: Option<u32>,
+
+    /// How the connection is secured [default: starttls]
+    #[arg(long, value_enum)]
+    pub tls: Option<TlsMode>,
+
+    /// Allow --tls plain, which sends the password unencrypted. Only for
+    /// local test servers.
+    #[arg(long)]
+    pub allow_plaintext: bool,
+
+    /// PEM file with additional CA certificates to trust
+    #[arg(long, value_name = "FILE")]
+    pub ca_file: Option<PathBuf>,
+
+    /// Only accept the server certificate with this SHA-256 fingerprint,
+    /// whoever issued it
+    #[arg(long, value_name = "FINGERPRINT")]
+    pub pin_sha256: Option<String>,
+
+    /// Client certificate for mutual TLS, PKCS#12 or PEM with --client-key
+    #[arg(long, value_name = "FILE")]
+    pub client_cert: Option<PathBuf>,
+
+    /// PKCS#8 PEM private key of a PEM --client-cert
+    #[arg(long, value_name = "FILE", requires = "client_cert")]
+    pub client_key: Option<PathBuf>,
+
+    /// Username, instead of reading it from the environment
+    #[arg(long)]
+    pub username: Option<String>,
+
+    /// Read the password from this file instead of the environment. Only
+    /// its owner may have access to it.
+    #[arg(long, value_name = "FILE", conflicts_with = "password_command")]
+    pub password_file: Option<PathBuf>,
+
+    /// Shell command printing the password, e.g. "pass show mail/work"
+    #[arg(long, value_name = "COMMAND")]
+    pub password_command: Option<String>,
+
+    /// How to authenticate [default: login]
+    #[arg(long, value_enum)]
+    pub auth: Option<AuthMethod>,
+
+    /// User to act as with --auth plain, while logging in with the
+    /// credentials of --username
+    #[arg(long, value_name = "USER")]
+    pub authzid: Option<String>,
+
+    /// File containing the OAuth2 access token for --auth xoauth2 or
+    /// oauthbearer
+    #[arg(long, value_name = "FILE", conflicts_with = "oauth_token_command")]
+    pub oauth_token_file: Option<PathBuf>,
+
+    /// Shell command printing the OAuth2 access token
+    #[arg(long, value_name = "COMMAND")]
+    pub oauth_token_command: Option<String>,
+
+    /// Source mailbox to archive, may be repeated. IMAP wildcards such as
+    /// "Projects/*" are allowed.
+    #[arg(long = "mailbox", value_name = "MAILBOX")]
+    pub mailboxes: Vec<String>,
+
+    /// Also archive the subfolders of the --mailbox sources
+    #[arg(long, requires = "mailboxes")]
+    pub recursive: bool,
+
+    /// Destination folder template, e.g. "Archive/{year}/{month:02}"
+    #[arg(long, value_name = "TEMPLATE")]
+    pub destination: Option<String>,
+
+    /// Period messages are grouped into [default: year]
+    #[arg(long, value_enum)]
+    pub granularity: Option<Granularity>,
+
+    /// Time zone the periods and the cutoff are in, e.g. "Europe/Berlin"
+    /// [default: UTC]
+    #[arg(long, value_name = "ZONE")]
+    pub timezone: Option<Tz>,
+
+    /// Where the date of a message is taken from, comma separated and
+    /// tried in order [default: internal]
+    #[arg(long, value_enum, value_delimiter = ',', value_name = "SOURCE")]
+    pub date_source: Vec<DateSource>,
+
+    /// What to do with messages none of the date sources has a date for
+    /// [default: skip]
+    #[arg(long, value_enum)]
+    pub undated: Option<UndatedPolicy>,
+
+    /// Folder undated messages are moved to with --undated move
+    /// [default: Archives/Undated]
+    #[arg(long, value_name = "FOLDER")]
+    pub undated_folder: Option<String>,
+
+    /// Archive whole threads once their newest message is old enough, into
+    /// the folder of their newest or oldest message. Fetches the threading
+    /// headers of every message in the mailbox on each run.
+    #[arg(long, value_enum, value_name = "MESSAGE")]
+    pub threads: Option<ThreadFolder>,
+
+    /// Flags that keep a message in place, comma separated
+    /// [default: flagged,draft,unseen]
+    #[arg(long, value_enum, value_delimiter = ',', value_name = "FLAG")]
+    pub protected_flags: Vec<ProtectedFlag>,
+
+    /// Keywords that keep a message in place, comma separated
+    /// [default: $Important,$Todo]
+    #[arg(long, value_delimiter = ',', value_name = "KEYWORD")]
+    pub protected_keywords: Vec<String>,
+
+    /// Archive messages whatever their flags and keywords are
+    #[arg(long, conflicts_with_all = ["protected_flags", "protected_keywords"])]
+    pub no_protection: bool,
+
+    /// Only archive messages older than this, e.g. "90d", "12w", "6m" or "1y"
+    /// [default: everything before the current period]
+    #[arg(long, value_name = "AGE", conflicts_with = "before")]
+    pub older_than: Option<String>,
+
+    /// Only archive messages from before this date, e.g. "2025-01-01"
+    #[arg(long, value_name = "DATE")]
+    pub before: Option<NaiveDate>,
+
+    /// Number of messages fetched and searched per IMAP command at first.
+    /// Batches grow while the server responds quickly and shrink when it
+    /// rejects them or times out. Moves are only limited by
+    /// --max-command-length. [default: 256]
+    #[arg(long)]
+    pub batch_size: Option<usize>,
+
+    /// Longest command line the server accepts, in bytes [default: 8192]
+    #[arg(long, value_name = "BYTES")]
+    pub max_command_length: Option<usize>,
+
+    /// Prefix for destination folders instead of the personal namespace
+    /// detected on the server, e.g. "INBOX."
+    #[arg(long, value_name = "PREFIX")]
+    pub folder_prefix: Option<String>,
+
+    /// On servers without MOVE and UIDPLUS, allow a plain EXPUNGE after
+    /// copying. This also removes any other message already marked as
+    /// deleted in the source mailbox.
+    #[arg(long)]
+    pub allow_expunge: bool,
+}
+
+///
+/// A `[[sources]]` entry: a mailbox (or IMAP wildcard pattern) with its
+/// own destination
+///
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct SourceConfig {
+    mailbox: String,
+    #[serde(default)]
+    recursive: bool,
+    destination: Option<String>,
+}
+
+///
+/// What happens to the messages a rule matches
+///
+#[derive(Debug, Clone, Copy, Default, PartialEq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum RuleAction {
+    /// Archive them once they pass the cutoff
+    #[default]
+    Archive,
+    /// Leave them where they are
+    Keep,
+}
+
+///
+/// A `[[rules]]` entry: search criteria, all of which a message has to
+/// match, and what happens to the matching messages
+///
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RuleConfig {
+    from: Option<String>,
+    to: Option<String>,
+    subject: Option<String>,
+    /// Name of a header field and text its value contains, empty to match
+    /// any message that has the field
+    header: Option<(String, String)>,
+    /// Messages bigger than this many bytes
+    larger: Option<u32>,
+    keyword: Option<String>,
+    /// Unread (true) or read (false) messages
+    unseen: Option<bool>,
+    flagged: Option<bool>,
+    /// Further criteria in IMAP SEARCH syntax
+    search: Option<String>,
+    #[serde(default)]
+    action: RuleAction,
+    /// Destination template instead of the one of the source
+    destination: Option<String>,
+}
+
+///
+/// The `[oauth]` table: where the access token for XOAUTH2 and OAUTHBEARER
+/// comes from. Exactly one of `token_file`, `token_command` and
+/// `token_url` must be given.
+///
+#[derive(Debug, Default, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct OAuthConfig {
+    token_file: Option<PathBuf>,
+    token_command: Option<String>,
+    /// Token endpoint a refresh token is exchanged at
+    token_url: Option<String>,
+    client_id: Option<String>,
+    client_secret_env: Option<String>,
+    refresh_token_env: Option<String>,
+    refresh_token_file: Option<PathBuf>,
+    scope: Option<String>,
+}
+
+///
+/// Settings of one account in the configuration file. Every key is
+/// optional so that accounts can inherit from the top level of the file
+/// and be combined with command line flags.
+///
+/// The top level of the file uses the same structure, with the addition
+/// of the `accounts` list, `concurrency` and `state_file`.
+///
+#[derive(Debug, Default, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct AccountConfig {
+    name: Option<String>,
+    server: Option<String>,
+    port: Option<u16>,
+    timeout: Option<u64>,
+    retries: Option<u32>,
+    tls: Option<TlsMode>,
+    allow_plaintext: Option<bool>,
+    ca_file: Option<PathBuf>,
+    pin_sha256: Option<String>,
+    client_cert: Option<PathBuf>,
+    client_key: Option<PathBuf>,
+    client_cert_password_env: Option<String>,
+    username: Option<String>,
+    username_env: Option<String>,
+    password_env: Option<String>,
+    password_file: Option<PathBuf>,
+    password_command: Option<String>,
+    password_secret_service: Option<BTreeMap<String, String>>,
+    password_keyring: Option<String>,
+    auth: Option<AuthMethod>,
+    authzid: Option<String>,
+    oauth: Option<OAuthConfig>,
+    mailboxes: Option<Vec<String>>,
+    sources: Option<Vec<SourceConfig>>,
+    rules: Option<Vec<RuleConfig>>,
+    destination: Option<String>,
+    granularity: Option<Granularity>,
+    timezone: Option<Tz>,
+    date_source: Option<Vec<DateSource>>,
+    undated: Option<UndatedPolicy>,
+    undated_folder: Option<String>,
+    protected_flags: Option<Vec<ProtectedFlag>>,
+    protected_keywords: Option<Vec<String>>,
+    threads: Option<ThreadFolder>,
+    older_than: Option<String>,
+    #[serde(default, deserialize_with = "deserialize_date")]
+    before: Option<NaiveDate>,
+    batch_size: Option<usize>,
+    max_command_length: Option<usize>,
+    folder_prefix: Option<String>,
+    allow_expunge: Option<bool>,
+
+    concurrency: Option<usize>,
+    state_file: Option<PathBuf>,
+    #[serde(default)]
+    accounts: Vec<AccountConfig>,
+}
+
+///
+/// Mailboxes to archive and where their messages go
+///
+#[derive(Debug)]
+pub struct Source {
+    /// Mailbox name on the server, may contain the wildcards `*` and `%`
+    pub mailbox: String,
+    /// Include the subfolders of the matched mailboxes
+    pub recursive: bool,
+    pub destination: Template,
+}
+
+///
+/// Selects messages by IMAP SEARCH criteria and decides what happens to
+/// them. The first rule a message matches applies.
+///
+#[derive(Debug)]
+pub struct Rule {
+    criteria: String,
+    pub action: RuleAction,
+    /// Destination of archived messages instead of the one of their source
+    pub destination: Option<Template>,
+}
+
+impl Rule {
+    ///
+    /// UID SEARCH arguments finding the messages of `uidset` the rule matches
+    ///
+    pub fn query(&self, uidset: &str) -> String {
+        format!("UID {uidset} {}", self.criteria)
+    }
+}
+
+///
+/// Check that a rule criterion can be sent in a SEARCH command. Quoted
+/// strings may only contain 7-bit text without CR or LF, and anything else
+/// needs a literal the server has to accept first, which the IMAP client
+/// cannot send as part of a SEARCH.
+///
+fn check_criterion(key: &str, value: &str) -> Result<()> {
+    if !value.is_ascii() {
+        bail!("The {key} of a rule may only contain ASCII text: {value:?}");
+    }
+    if value.chars().any(|c| c.is_ascii_control()) {
+        bail!("The {key} of a rule may not contain control characters: {value:?}");
+    }
+    Ok(())
+}
+
+///
+/// Fully resolved settings for one account
+///
+#[derive(Debug)]
+pub struct Account {
+    pub name: String,
+    pub server: String,
+    pub port: u16,
+    /// How long to wait for the server to respond
+    pub timeout: Duration,
+    /// How often to reconnect after the connection broke
+    pub retries: u32,
+    pub tls: TlsMode,
+    pub ca_file: Option<PathBuf>,
+    pub pin_sha256: Option<Fingerprint>,
+    pub client_cert: Option<ClientCert>,
+    pub username: String,
+    pub auth: AuthMethod,
+    /// User whose mailboxes are archived when logging in as an admin
+    pub authzid: Option<String>,
+    pub credentials: Credentials,
+    pub sources: Vec<Source>,
+    pub rules: Vec<Rule>,
+    pub granularity: Granularity,
+    /// Zone whose midnight starts days and periods
+    pub timezone: Tz,
+    /// Where message dates come from, the first one a message has wins
+    pub date_sources: Vec<DateSource>,
+    pub undated: UndatedPolicy,
+    /// Where undated messages go with `UndatedPolicy::Move`
+    pub undated_folder: String,
+    /// Messages with any of these flags or keywords are never archived
+    pub protected_flags: Vec<ProtectedFlag>,
+    pub protected_keywords: Vec<String>,
+    /// Keep threads together, in the folder of the given message
+    pub threads: Option<ThreadFolder>,
+    pub cutoff: Cutoff,
+    /// Initial number of messages fetched or searched per command, which is
+    /// then adapted to how fast the server responds
+    pub batch_size: usize,
+    pub max_command_length: usize,
+    pub folder_prefix: Option<String>,
+    /// Fall back to a plain EXPUNGE when the server lacks MOVE and UIDPLUS
+    pub allow_expunge: bool,
+}
+
+///
+/// Everything needed for one run
+///
+#[derive(Debug)]
+pub struct Config {
+    pub accounts: Vec<Account>,
+    pub concurrency: usize,
+    pub dry_run: bool,
+    pub plan_file: Option<PathBuf>,
+    pub state_file: Option<PathBuf>,
+    pub full_scan: bool,
+}
+
+impl AccountConfig {
+    fn load(path: &Path) -> Result<AccountConfig> {
+        let contents = fs::read_to_string(path)
+            .with_context(|| format!("Failed to read config file {}", path.display()))?;
+        toml::from_str(&contents).with_context(|| format!("Invalid config file {}", path.display()))
+    }
+
+    ///
+    /// Account settings given on the command line
+    ///
+    fn from_args(args: &Args) -> AccountConfig {
+        AccountConfig {
+            server: args.server.clone(),
+            port: args.port,
+            timeout: args.timeout,
+            retries: args.retries,
+            tls: args.tls,
+            allow_plaintext: args.allow_plaintext.then_some(true),
+            ca_file: args.ca_file.clone(),
+            pin_sha256: args.pin_sha256.clone(),
+            client_cert: args.client_cert.clone(),
+            client_key: args.client_key.clone(),
+            username: args.username.clone(),
+            password_file: args.password_file.clone(),
+            password_command: args.password_command.clone(),
+            auth: args.auth,
+            authzid: args.authzid.clone(),
+            oauth: (args.oauth_token_file.is_some() || args.oauth_token_command.is_some()).then(
+                || OAuthConfig {
+                    token_file: args.oauth_token_file.clone(),
+                    token_command: args.oauth_token_command.clone(),
+                    ..OAuthConfig::default()
+                },
+            ),
+            mailboxes: (!args.mailboxes.is_empty() && !args.recursive)
+                .then(|| args.mailboxes.clone()),
+            sources: (!args.mailboxes.is_empty() && args.recursive).then(|| {
+                args.mailboxes
+                    .iter()
+                    .map(|mailbox| SourceConfig {
+                        mailbox: mailbox.clone(),
+                        recursive: true,
+                        destination: None,
+                    })
+                    .collect()
+            }),
+            destination: args.destination.clone(),
+            granularity: args.granularity,
+            timezone: args.timezone,
+            date_source: (!args.date_source.is_empty()).then(|| args.date_source.clone()),
+            undated: args.undated,
+            undated_folder: args.undated_folder.clone(),
+            protected_flags: if args.no_protection {
+                Some(Vec::new())
+            } else {
+                (!args.protected_flags.is_empty()).then(|| args.protected_flags.clone())
+            },
+            protected_keywords: if args.no_protection {
+                Some(Vec::new())
+            } else {
+                (!args.protected_keywords.is_empty()).then(|| args.protected_keywords.clone())
+            },
+            threads: args.threads,
+            older_than: args.older_than.clone(),
+            before: args.before,
+            batch_size: args.batch_size,
+            max_command_length: args.max_command_length,
+            folder_prefix: args.folder_prefix.clone(),
+            allow_expunge: args.allow_expunge.then_some(true),
+            ..AccountConfig::default()
+        }
+    }
+
+    ///
+    /// Fill in every setting missing from `self` from `defaults`
+    ///
+    fn or(self, defaults: &AccountConfig) -> AccountConfig {
+        // The ways of reading the password replace each other
+        let no_password = AccountConfig::default();
+        let password_defaults = if self.password_sources().is_empty() {
+            defaults
+        } else {
+            &no_password
+        };
+        // As do the two ways of giving the cutoff
+        let (older_than, before) = if self.older_than.is_some() || self.before.is_some() {
+            (self.older_than, self.before)
+        } else {
+            (defaults.older_than.clone(), defaults.before)
+        };
+        // And the two ways of listing source mailboxes
+        let (mailboxes, sources) = if self.mailboxes.is_some() || self.sources.is_some() {
+            (self.mailboxes, self.sources)
+        } else {
+            (defaults.mailboxes.clone(), defaults.sources.clone())
+        };
+
+        AccountConfig {
+            name: self.name.or_else(|| defaults.name.clone()),
+            server: self.server.or_else(|| defaults.server.clone()),
+            port: self.port.or(defaults.port),
+            timeout: self.timeout.or(defaults.timeout),
+            retries: self.retries.or(defaults.retries),
+            tls: self.tls.or(defaults.tls),
+            allow_plaintext: self.allow_plaintext.or(defaults.allow_plaintext),
+            ca_file: self.ca_file.or_else(|| defaults.ca_file.clone()),
+            pin_sha256: self.pin_sha256.or_else(|| defaults.pin_sha256.clone()),
+            client_cert: self.client_cert.or_else(|| defaults.client_cert.clone()),
+            client_key: self.client_key.or_else(|| defaults.client_key.clone()),
+            client_cert_password_env: self
+                .client_cert_password_env
+                .or_else(|| defaults.client_cert_password_env.clone()),
+            username: self.username.or_else(|| defaults.username.clone()),
+            username_env: self.username_env.or_else(|| defaults.username_env.clone()),
+            password_env: self
+                .password_env
+                .or_else(|| password_defaults.password_env.clone()),
+            password_file: self
+                .password_file
+                .or_else(|| password_defaults.password_file.clone()),
+            password_command: self
+                .password_command
+                .or_else(|| password_defaults.password_command.clone()),
+            password_secret_service: self
+                .password_secret_service
+                .or_else(|| password_defaults.password_secret_service.clone()),
+            password_keyring: self
+                .password_keyring
+                .or_else(|| password_defaults.password_keyring.clone()),
+            auth: self.auth.or(defaults.auth),
+            authzid: self.authzid.or_else(|| defaults.authzid.clone()),
+            oauth: self.oauth.or_else(|| defaults.oauth.clone()),
+            mailboxes,
+            sources,
+            rules: self.rules.or_else(|| defaults.rules.clone()),
+            destination: self.destination.or_else(|| defaults.destination.clone()),
+            granularity: self.granularity.or(defaults.granularity),
+            timezone: self.timezone.or(defaults.timezone),
+            date_source: self.date_source.or_else(|| defaults.date_source.clone()),
+            undated: self.undated.or(defaults.undated),
+            undated_folder: self
+                .undated_folder
+                .or_else(|| defaults.undated_folder.clone()),
+            protected_flags: self
+                .protected_flags
+                .or_else(|| defaults.protected_flags.clone()),
+            protected_keywords: self
+                .protected_keywords
+                .or_else(|| defaults.protected_keywords.clone()),
+            threads: self.threads.or(defaults.threads),
+            older_than,
+            before,
+            batch_size: self.batch_size.or(defaults.batch_size),
+            max_command_length: self.max_command_length.or(defaults.max_command_length),
+            folder_prefix: self
+                .folder_prefix
+                .or_else(|| defaults.folder_prefix.clone()),
+            allow_expunge: self.allow_expunge.or(defaults.allow_expunge),
+            concurrency: None,
+            state_file: None,
+            accounts: Vec::new(),
+        }
+    }
+
+    ///
+    /// Every configured way of reading the password
+    ///
+    fn password_sources(&self) -> Vec<CredentialSource> {
+        let mut sources = Vec::new();
+        if let Some(name) = &self.password_env {
+            sources.push(CredentialSource::Env(name.clone()));
+        }
+        if let Some(path) = &self.password_file {
+            sources.push(CredentialSource::File(path.clone()));
+        }
+        if let Some(command) = &self.password_command {
+            sources.push(CredentialSource::Command(command.clone()));
+        }
+        if let Some(attributes) = &self.password_secret_service {
+            sources.push(CredentialSource::SecretService(attributes.clone()));
+        }
+        if let Some(description) = &self.password_keyring {
+            sources.push(CredentialSource::Keyring(description.clone()));
+        }
+        sources
+    }
+
+    ///
+    /// Validate the merged settings and fill in defaults
+    ///
+    fn resolve(self) -> Result<Account> {
+        let password_sources = self.password_sources();
+        let server = match self.server {
+            Some(server) => server,
+            None => bail!("No server configured, set `server` in the config file or pass --server"),
+        };
+
+        let tls = self.tls.unwrap_or_default();
+        if tls == TlsMode::Plain && !self.allow_plaintext.unwrap_or(false) {
+            bail!(
+                "`tls = \"plain\"` sends the password unencrypted, set `allow_plaintext` \
+                 or pass --allow-plaintext if this really is a local test server"
+            );
+        }
+
+        let pin_sha256 = match &self.pin_sha256 {
+            Some(pin) => Some(parse_fingerprint(pin).context("Invalid `pin_sha256`")?),
+            None => None,
+        };
+        let client_cert = match (self.client_cert, self.client_key) {
+            (Some(cert_file), Some(key_file)) => Some(ClientCert::Pem {
+                cert_file,
+                key_file,
+            }),
+            (Some(file), None) => Some(ClientCert::Pkcs12 {
+                file,
+                password_env: self.client_cert_password_env,
+            }),
+            (None, Some(_)) => bail!("`client_key` is set without `client_cert`"),
+            (None, None) => None,
+        };
+
+        let username = match self.username {
+            Some(username) => username,
+            None => read_env(self.username_env.as_deref().unwrap_or(DEFAULT_USERNAME_ENV))?,
+        };
+        let auth = self.auth.unwrap_or_default();
+        if self.authzid.is_some() && auth != AuthMethod::Plain {
+            bail!("`authzid` is only supported with `auth = \"plain\"`");
+        }
+        let credentials = match auth {
+            AuthMethod::Login | AuthMethod::Plain | AuthMethod::CramMd5 => {
+                let mut sources = password_sources;
+                let source = match sources.len() {
+                    0 => CredentialSource::Env(String::from(DEFAULT_PASSWORD_ENV)),
+                    1 => sources.remove(0),
+                    _ => bail!(
+                        "Only one of `password_env`, `password_file`, `password_command`, \
+                         `password_secret_service` and `password_keyring` can be set"
+                    ),
+                };
+                Credentials::Password(source)
+            }
+            AuthMethod::Xoauth2 | AuthMethod::Oauthbearer => match self.oauth {
+                Some(oauth) => Credentials::OAuth(oauth.resolve().context("Invalid `[oauth]`")?),
+                None => bail!(
+                    "`auth = \"{auth}\"` needs an [oauth] table, --oauth-token-file or \
+                     --oauth-token-command"
+                ),
+            },
+        };
+
+        let granularity = self.granularity.unwrap_or_default();
+        let mut date_sources = self
+            .date_source
+            .unwrap_or_else(|| vec![DateSource::Internal]);
+        if date_sources.is_empty() {
+            bail!("`date_source` needs at least one source");
+        }
+        let undated = self.undated.unwrap_or_default();
+        if undated == UndatedPolicy::Internal && !date_sources.contains(&DateSource::Internal) {
+            date_sources.push(DateSource::Internal);
+        }
+        let protected_keywords = self.protected_keywords.unwrap_or_else(|| {
+            DEFAULT_PROTECTED_KEYWORDS
+                .iter()
+                .map(|keyword| keyword.to_string())
+                .collect()
+        });
+        if let Some(keyword) = protected_keywords
+            .iter()
+            .find(|keyword| !flags::is_keyword(keyword))
+        {
+            bail!("Invalid protected keyword {keyword:?}");
+        }
+        let mut entries: Vec<SourceConfig> = self
+            .mailboxes
+            .unwrap_or_default()
+            .into_iter()
+            .map(|mailbox| SourceConfig {
+                mailbox,
+                recursive: false,
+                destination: None,
+            })
+            .chain(self.sources.unwrap_or_default())
+            .collect();
+        if entries.is_empty() {
+            entries.push(SourceConfig {
+                mailbox: String::from(DEFAULT_MAILBOX),
+                recursive: false,
+                destination: None,
+            });
+        }
+
+        let mut sources = Vec::new();
+        for entry in entries {
+            let nested = entry.recursive || entry.mailbox.contains(['*', '%']);
+            let template = match (&entry.destination, &self.destination) {
+                (Some(destination), _) | (None, Some(destination)) => destination,
+                (None, None) if nested => granularity.default_nested_template(),
+                (None, None) => granularity.default_template(),
+            };
+            let source = Source::resolve(
+                entry.mailbox.clone(),
+                entry.recursive,
+                template,
+                granularity,
+            )
+            .with_context(|| format!("Invalid destination for source {:?}", entry.mailbox))?;
+            sources.push(source);
+        }
+
+        let rules = self
+            .rules
+            .unwrap_or_default()
+            .into_iter()
+            .enumerate()
+            .map(|(index, rule)| {
+                rule.resolve(granularity)
+                    .with_context(|| format!("Invalid rule #{}", index + 1))
+            })
+            .collect::<Result<Vec<_>>>()?;
+
+        let cutoff = match (self.older_than, self.before) {
+            (Some(_), Some(_)) => bail!("Only one of `older_than` and `before` can be set"),
+            (Some(age), None) => {
+                Cutoff::OlderThan(Age::parse(&age).context("Invalid `older_than`")?)
+            }
+            (None, Some(date)) => Cutoff::Before(date),
+            (None, None) => Cutoff::CurrentPeriod,
+        };
+
+        let batch_size = self.batch_size.unwrap_or(DEFAULT_BATCH_SIZE);
+        if batch_size == 0 {
+            bail!("`batch_size` must be at least 1");
+        }
+        let max_command_length = self
+            .max_command_length
+            .unwrap_or(DEFAULT_MAX_COMMAND_LENGTH);
+        if max_command_length < MIN_COMMAND_LENGTH {
+            bail!("`max_command_length` must be at least {MIN_COMMAND_LENGTH}");
+        }
+        let timeout = self.timeout.unwrap_or(DEFAULT_TIMEOUT);
+        if timeout == 0 {
+            bail!("`timeout` must be at least 1 second");
+        }
+
+        Ok(Account {
+            name: self.name.unwrap_or_else(|| {
+                // Acting as another user archives the mailboxes of that user
+                let user = self.authzid.as_ref().unwrap_or(&username);
+                format!("{user}@{server}")
+            }),
+            server,
+            port: self.port.unwrap_or(tls.default_port()),
+            timeout: Duration::from_secs(timeout),
+            retries: self.retries.unwrap_or(DEFAULT_RETRIES),
+            tls,
+            ca_file: self.ca_file,
+            pin_sha256,
+            client_cert,
+            username,
+            auth,
+            authzid: self.authzid,
+            credentials,
+            sources,
+            rules,
+            granularity,
+            timezone: self.timezone.unwrap_or(Tz::UTC),
+            date_sources,
+            undated,
+            undated_folder: self
+                .undated_folder
+                .unwrap_or_else(|| DEFAULT_UNDATED_FOLDER.to_string()),
+            protected_flags: self
+                .protected_flags
+                .unwrap_or_else(|| DEFAULT_PROTECTED_FLAGS.to_vec()),
+            protected_keywords,
+            threads: self.threads,
+            cutoff,
+            batch_size,
+            max_command_length,
+            folder_prefix: self.folder_prefix,
+            allow_expunge: self.allow_expunge.unwrap_or(false),
+        })
+    }
+}
+
+impl OAuthConfig {
+    fn resolve(self) -> Result<TokenSource> {
+        match (self.token_file, self.token_command, self.token_url) {
+            (Some(path), None, None) => Ok(TokenSource::File(path)),
+            (None, Some(command), None) => Ok(TokenSource::Command(command)),
+            (None, None, Some(token_url)) => {
+                let Some(client_id) = self.client_id else {
+                    bail!("`token_url` needs a `client_id`");
+                };
+                let refresh_token = match (self.refresh_token_env, self.refresh_token_file) {
+                    (Some(name), None) => CredentialSource::Env(name),
+                    (None, Some(path)) => CredentialSource::File(path),
+                    _ => bail!(
+                        "`token_url` needs one of `refresh_token_env` and `refresh_token_file`"
+                    ),
+                };
+                let client_secret = self.client_secret_env.map(CredentialSource::Env);
+                Ok(TokenSource::Refresh {
+                    token_url,
+                    client_id,
+                    client_secret,
+                    refresh_token,
+                    scope: self.scope,
+                })
+            }
+            _ => bail!("Set exactly one of `token_file`, `token_command` and `token_url`"),
+        }
+    }
+}
+
+impl RuleConfig {
+    fn resolve(self, granularity: Granularity) -> Result<Rule> {
+        let mut criteria = Vec::new();
+        for (key, value) in [
+            ("FROM", &self.from),
+            ("TO", &self.to),
+            ("SUBJECT", &self.subject),
+        ] {
+            if let Some(value) = value {
+                check_criterion(&key.to_lowercase(), value)?;
+                criteria.push(format!("{key} {}", quote(value)));
+            }
+        }
+        if let Some((name, value)) = &self.header {
+            check_criterion("header", name)?;
+            check_criterion("header", value)?;
+            criteria.push(format!("HEADER {} {}", quote(name), quote(value)));
+        }
+        if let Some(size) = self.larger {
+            criteria.push(format!("LARGER {size}"));
+        }
+        if let Some(keyword) = &self.keyword {
+            if !flags::is_keyword(keyword) {
+                bail!("Invalid keyword {keyword:?}");
+            }
+            criteria.push(format!("KEYWORD {keyword}"));
+        }
+        match self.unseen {
+            Some(true) => criteria.push("UNSEEN".to_string()),
+            Some(false) => criteria.push("SEEN".to_string()),
+            None => {}
+        }
+        match self.flagged {
+            Some(true) => criteria.push("FLAGGED".to_string()),
+            Some(false) => criteria.push("UNFLAGGED".to_string()),
+            None => {}
+        }
+        if let Some(search) = &self.search {
+            check_criterion("search", search)?;
+            criteria.push(format!("({search})"));
+        }
+        if criteria.is_empty() {
+            bail!("A rule needs at least one criterion");
+        }
+
+        let destination = match (self.action, self.destination) {
+            (RuleAction::Keep, Some(_)) => bail!("A rule that keeps messages has no destination"),
+            (_, Some(destination)) => {
+                let template = Template::parse(&destination)?;
+                template.check_granularity(granularity)?;
+                Some(template)
+            }
+            (_, None) => None,
+        };
+
+        Ok(Rule {
+            criteria: criteria.join(" "),
+            action: self.action,
+            destination,
+        })
+    }
+}
+
+impl Source {
+    fn resolve(
+        mailbox: String,
+        recursive: bool,
+        destination: &str,
+        granularity: Granularity,
+    ) -> Result<Source> {
+        let destination = Template::parse(destination)?;
+        destination.check_granularity(granularity)?;
+
+        // The archive folders must be recognizable so that they are not
+        // matched again by the source themselves, and the messages of
+        // different mailboxes must not end up in the same folder
+        let wildcard = mailbox.contains(['*', '%']);
+        if (recursive || wildcard)
+            && (destination.static_prefix().is_empty() || !destination.uses_mailbox())
+        {
+            bail!(
+                "Destination {destination} of a recursive or wildcard source must start \
+                 with a fixed folder and contain {{source}} or {{subfolder}}, e.g. {:?}",
+                granularity.default_nested_template()
+            );
+        }
+
+        Ok(Source {
+            mailbox,
+            recursive,
+            destination,
+        })
+    }
+}
+
+///
+/// Read a TOML date such as `2025-01-01`
+///
+fn deserialize_date<'de, D: Deserializer<'de>>(
+    deserializer: D,
+) -> Result<Option<NaiveDate>, D::Error> {
+    let value = toml::value::Datetime::deserialize(deserializer)?;
+    match (value.date, value.time) {
+        (Some(date), None) => {
+            NaiveDate::from_ymd_opt(date.year.into(), date.month.into(), date.day.into())
+                .map(Some)
+                .ok_or_else(|| D::Error::custom(format!("invalid date {value}")))
+        }
+        _ => Err(D::Error::custom(format!(
+            "expected a date like 2025-01-01, got {value}"
+        ))),
+    }
+}
+
+///
+/// Load the configuration file (if any) and merge it with the command line
+///
+pub fn load(args: Args) -> Result<Config> {
+    let mut file = match &args.config {
+        Some(path) => AccountConfig::load(path)?,
+        None if Path::new(DEFAULT_CONFIG_FILE).exists() => {
+            AccountConfig::load(Path::new(DEFAULT_CONFIG_FILE))?
+        }
+        None => AccountConfig::default(),
+    };
+
+    let concurrency = args
+        .concurrency
+        .or(file.concurrency)
+        .unwrap_or(DEFAULT_CONCURRENCY);
+    if concurrency == 0 {
+        bail!("`concurrency` must be at least 1");
+    }
+
+    // Without an `accounts` list the top level describes the only account
+    let entries = match std::mem::take(&mut file.accounts) {
+        entries if entries.is_empty() => vec![AccountConfig::default()],
+        entries => {
+            // The name at the top level only applies to a single account
+            file.name = None;
+            entries
+        }
+    };
+
+    let overrides = AccountConfig::from_args(&args);
+    let mut accounts = Vec::new();
+    for (index, entry) in entries.into_iter().enumerate() {
+        if entry.concurrency.is_some() || entry.state_file.is_some() || !entry.accounts.is_empty() {
+            bail!(
+                "`concurrency`, `state_file` and `accounts` are only allowed at the top level \
+                 of the config file"
+            );
+        }
+
+        let entry = entry.or(&file);
+        if !args.accounts.is_empty()
+            && !entry
+                .name
+                .as_ref()
+                .is_some_and(|name| args.accounts.contains(name))
+        {
+            continue;
+        }
+
+        let label = entry
+            .name
+            .clone()
+            .unwrap_or_else(|| format!("#{}", index + 1));
+        let account = overrides
+            .clone()
+            .or(&entry)
+            .resolve()
+            .with_context(|| format!("Invalid settings for account {label}"))?;
+        accounts.push(account);
+    }
+
+    if accounts.is_empty() {
+        bail!("No accounts matched {:?}", args.accounts);
+    }
+    // Checkpoints and the plan are kept by account name
+    let mut names = HashSet::new();
+    if let Some(account) = accounts.iter().find(|account| !names.insert(&account.name)) {
+        bail!(
+            "Several accounts are named {:?}, set `name` to tell them apart",
+            account.name
+        );
+    }
+
+    Ok(Config {
+        accounts,
+        concurrency,
+        dry_run: args.dry_run,
+        plan_file: args.plan,
+        state_file: args.state_file.or(file.state_file),
+        full_scan: args.full_scan,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn account(toml: &str) -> AccountConfig {
+        toml::from_str(toml).unwrap()
+    }
+
+    #[test]
+    fn merge_order() {
+        let top = account("server = \"top\"\nport = 1\ntimeout = 10\nretries = 3");
+        let entry = account("name = \"work\"\nserver = \"entry\"\nport = 2");
+        let cli = AccountConfig::from_args(&Args::parse_from([
+            "imap-archive",
+            "--server",
+            "cli",
+            "--retries",
+            "7",
+        ]));
+
+        let merged = cli.or(&entry.or(&top));
+        assert_eq!(merged.name.as_deref(), Some("work"));
+        assert_eq!(merged.server.as_deref(), Some("cli"));
+        assert_eq!(merged.port, Some(2));
+        assert_eq!(merged.timeout, Some(10));
+        assert_eq!(merged.retries, Some(7));
+    }
+
+    #[test]
+    fn duplicate_account_names() {
+        let path = std::env::temp_dir().join(format!(
+            "imap-archive-test-{}-accounts.toml",
+            std::process::id()
+        ));
+        fs::write(
+            &path,
+            "username = \"me\"\n\
+             [[accounts]]\nserver = \"imap.example.org\"\n\
+             [[accounts]]\nserver = \"imap.example.org\"\nport = 143\n",
+        )
+        .unwrap();
+        let args = Args::parse_from(["imap-archive", "--config", path.to_str().unwrap()]);
+        let result = load(args);
+        fs::remove_file(&path).unwrap();
+        assert!(result.unwrap_err().to_string().contains("Several accounts"));
+    }
+
+    #[test]
+    fn replaced_settings() {
+        let top = account(
+            "password_env = \"TOP_PASSWORD\"\n\
+             older_than = \"90d\"\n\
+             mailboxes = [\"INBOX\"]",
+        );
+
+        let entry = account(
+            "password_file = \"/etc/imap-password\"\n\
+             before = 2025-01-01\n\
+             [[sources]]\nmailbox = \"Projects\"\nrecursive = true",
+        );
+        let merged = entry.or(&top);
+        assert_eq!(merged.password_env, None);
+        assert_eq!(
+            merged.password_file.as_deref(),
+            Some(Path::new("/etc/imap-password"))
+        );
+        assert_eq!(merged.older_than, None);
+        assert_eq!(merged.before, NaiveDate::from_ymd_opt(2025, 1, 1));
+        assert_eq!(merged.mailboxes, None);
+        assert_eq!(merged.sources.map(|sources| sources.len()), Some(1));
+
+        let merged = AccountConfig::default().or(&top);
+        assert_eq!(merged.password_env.as_deref(), Some("TOP_PASSWORD"));
+        assert_eq!(merged.older_than.as_deref(), Some("90d"));
+        assert_eq!(merged.mailboxes, Some(vec!["INBOX".to_string()]));
+    }
+
+    fn rule(toml: &str) -> Result<Rule> {
+        let config: RuleConfig = toml::from_str(toml)?;
+        config.resolve(Granularity::Year)
+    }
+
+    #[test]
+    fn rule_criteria() {
+        let boss = rule(
+            "from = \"boss@example.org\"\n\
+             header = [\"List-Id\", \"\"]\n\
+             unseen = false\n\
+             search = \"LARGER 1000\"",
+        )
+        .unwrap();
+        assert_eq!(
+            boss.query("1:10"),
+            "UID 1:10 FROM \"boss@example.org\" HEADER \"List-Id\" \"\" SEEN (LARGER 1000)"
+        );
+        assert!(rule("").is_err());
+        assert!(rule("keyword = \"a b\"").is_err());
+    }
+
+    #[test]
+    fn rule_criteria_are_ascii() {
+        assert!(rule("subject = \"Grüße\"").is_err());
+        assert!(rule("from = \"a\\r\\nb LOGOUT\"").is_err());
+        assert!(rule("header = [\"X-Tag\", \"a\\nb\"]").is_err());
+        assert!(rule("search = \"SUBJECT Ölpreis\"").is_err());
+    }
+}
@@ -0,0 +1,295 @@
+use crate::config::Account;
+use crate::credentials::read_env;
+use anyhow::{bail, Context, Result};
+use clap::ValueEnum;
+use imap::Client;
+use native_tls::{Certificate, Identity, TlsConnector, TlsStream};
+use serde::Deserialize;
+use sha2::{Digest, Sha256};
+use std::fs;
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::net::TcpStream;
+use std::path::{Path, PathBuf};
+
+///
+/// How the connection to the server is secured
+///
+#[derive(Debug, Clone, Copy, Default, PartialEq, Deserialize, ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum TlsMode {
+    /// TLS from the first byte, usually on port 993
+    Implicit,
+    /// Plain connection upgraded with STARTTLS, usually on port 143
+    #[default]
+    Starttls,
+    /// No encryption at all, only meant for local test servers
+    Plain,
+}
+
+impl TlsMode {
+    ///
+    /// Port used when none is configured
+    ///
+    pub fn default_port(self) -> u16 {
+        match self {
+            TlsMode::Implicit => 993,
+            TlsMode::Starttls | TlsMode::Plain => 143,
+        }
+    }
+}
+
+///
+/// SHA-256 fingerprint of a certificate
+///
+pub type Fingerprint = [u8; 32];
+
+///
+/// Parse a SHA-256 fingerprint given as hex digits, optionally separated
+/// by colons as printed by `openssl x509 -fingerprint -sha256`
+///
+pub fn parse_fingerprint(text: &str) -> Result<Fingerprint> {
+    let digits: Vec<u8> = text.bytes().filter(|c| *c != b':').collect();
+    let mut fingerprint = Fingerprint::default();
+    if digits.len() != 2 * fingerprint.len() || !digits.iter().all(u8::is_ascii_hexdigit) {
+        bail!("Expected 64 hex digits in SHA-256 fingerprint {text:?}");
+    }
+    for (byte, pair) in fingerprint.iter_mut().zip(digits.chunks(2)) {
+        *byte = u8::from_str_radix(std::str::from_utf8(pair)?, 16)?;
+    }
+    Ok(fingerprint)
+}
+
+fn format_fingerprint(fingerprint: &[u8]) -> String {
+    fingerprint
+        .iter()
+        .map(|byte| format!("{byte:02X}"))
+        .collect::<Vec<_>>()
+        .join(":")
+}
+
+///
+/// Certificate presented to the server for mutual TLS
+///
+#[derive(Debug)]
+pub enum ClientCert {
+    /// PKCS#12 archive holding the certificate and its key, with the
+    /// env var holding its password
+    Pkcs12 {
+        file: PathBuf,
+        password_env: Option<String>,
+    },
+    /// PEM certificate (chain) and PKCS#8 PEM private key
+    Pem {
+        cert_file: PathBuf,
+        key_file: PathBuf,
+    },
+}
+
+impl ClientCert {
+    fn load(&self) -> Result<Identity> {
+        match self {
+            ClientCert::Pkcs12 { file, password_env } => {
+                let password = match password_env {
+                    Some(name) => read_env(name)?,
+                    None => String::new(),
+                };
+                Identity::from_pkcs12(&read_file(file)?, &password).with_context(|| {
+                    format!("Invalid PKCS#12 client certificate {}", file.display())
+                })
+            }
+            ClientCert::Pem {
+                cert_file,
+                key_file,
+            } => Identity::from_pkcs8(&read_file(cert_file)?, &read_file(key_file)?).with_context(
+                || {
+                    format!(
+                        "Invalid client certificate {} or key {}",
+                        cert_file.display(),
+                        key_file.display()
+                    )
+                },
+            ),
+        }
+    }
+}
+
+fn read_file(path: &Path) -> Result<Vec<u8>> {
+    fs::read(path).with_context(|| format!("Failed to read {}", path.display()))
+}
+
+///
+/// Read every certificate of a PEM bundle
+///
+fn read_certificates(path: &Path) -> Result<Vec<Certificate>> {
+    let pem = String::from_utf8_lossy(&read_file(path)?).into_owned();
+    let certificates = pem
+        .split_inclusive("-----END CERTIFICATE-----")
+        .filter(|block| block.contains("-----BEGIN CERTIFICATE-----"))
+        .map(|block| Certificate::from_pem(block.as_bytes()))
+        .collect::<Result<Vec<_>, _>>()
+        .with_context(|| format!("Invalid certificate in {}", path.display()))?;
+    if certificates.is_empty() {
+        bail!("No PEM certificates found in {}", path.display());
+    }
+    Ok(certificates)
+}
+
+///
+/// The connection to the server, encrypted or not depending on the
+/// configured `TlsMode`
+///
+#[derive(Debug)]
+pub enum Stream {
+    Tls(TlsStream<TcpStream>),
+    Plain(TcpStream),
+}
+
+impl Read for Stream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self {
+            Stream::Tls(stream) => stream.read(buf),
+            Stream::Plain(stream) => stream.read(buf),
+        }
+    }
+}
+
+impl Write for Stream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            Stream::Tls(stream) => stream.write(buf),
+            Stream::Plain(stream) => stream.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            Stream::Tls(stream) => stream.flush(),
+            Stream::Plain(stream) => stream.flush(),
+        }
+    }
+}
+
+///
+/// Read the greeting and ask the server to start TLS (RFC 3501 6.2.1). The
+/// imap crate does not hand out the socket after doing this itself.
+///
+fn starttls(tcp: &TcpStream) -> Result<()> {
+    let mut reader = BufReader::new(tcp);
+    let mut line = String::new();
+    reader.read_line(&mut line)?;
+    if line.starts_with("* BYE") {
+        bail!("Server refused the connection: {}", line.trim_end());
+    }
+
+    (&*tcp).write_all(b"a0 STARTTLS\r\n")?;
+    loop {
+        line.clear();
+        if reader.read_line(&mut line)? == 0 {
+            bail!("Connection closed during STARTTLS");
+        }
+        if let Some(status) = line.strip_prefix("a0 ") {
+            if status.starts_with("OK") {
+                return Ok(());
+            }
+            bail!("Server refused STARTTLS: {}", status.trim_end());
+        }
+    }
+}
+
+///
+/// Build the TLS settings of the account on top of the system defaults
+///
+fn tls_connector(account: &Account) -> Result<TlsConnector> {
+    let mut builder = TlsConnector::builder();
+    if let Some(ca_file) = &account.ca_file {
+        for certificate in read_certificates(ca_file)? {
+            builder.add_root_certificate(certificate);
+        }
+    }
+    if account.pin_sha256.is_some() {
+        // The pin replaces the usual checks, which would reject a
+        // self-signed certificate
+        builder.danger_accept_invalid_certs(true);
+    }
+    if let Some(client_cert) = &account.client_cert {
+        builder.identity(client_cert.load()?);
+    }
+    Ok(builder.build()?)
+}
+
+///
+/// Perform the TLS handshake on an open connection and check the pinned
+/// certificate, if any
+///
+fn handshake(account: &Account, tcp: TcpStream) -> Result<TlsStream<TcpStream>> {
+    let server = account.server.as_str();
+    let stream = tls_connector(account)?
+        .connect(server, tcp)
+        .with_context(|| format!("TLS handshake with {server} failed"))?;
+
+    if let Some(pin) = &account.pin_sha256 {
+        let Some(certificate) = stream.peer_certificate()? else {
+            bail!("{server} did not present a certificate");
+        };
+        let fingerprint = Sha256::digest(certificate.to_der()?);
+        if fingerprint.as_slice() != pin {
+            bail!(
+                "Certificate of {server} has SHA-256 fingerprint {}, expected {}",
+                format_fingerprint(&fingerprint),
+                format_fingerprint(pin)
+            );
+        }
+    }
+    Ok(stream)
+}
+
+///
+/// Open a connection to the server of the given account and read the
+/// greeting, ready to log in
+///
+pub fn connect(account: &Account) -> Result<Client<Stream>> {
+    let server = account.server.as_str();
+    let tcp = TcpStream::connect((server, account.port))
+        .with_context(|| format!("Failed to connect to {server}:{}", account.port))?;
+    tcp.set_read_timeout(Some(account.timeout))?;
+    tcp.set_write_timeout(Some(account.timeout))?;
+
+    let stream = match account.tls {
+        TlsMode::Implicit => Stream::Tls(handshake(account, tcp)?),
+        TlsMode::Starttls => {
+            starttls(&tcp)?;
+            Stream::Tls(handshake(account, tcp)?)
+        }
+        TlsMode::Plain => Stream::Plain(tcp),
+    };
+
+    let mut client = Client::new(stream);
+    if account.tls != TlsMode::Starttls {
+        client.read_greeting()?;
+    }
+    Ok(client)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn fingerprints() {
+        let hex = "9F86D081884C7D659A2FEAA0C55AD015A3BF4F1B2B0B822CD15D6C15B0F00A08";
+        let fingerprint = parse_fingerprint(hex).unwrap();
+        assert_eq!(fingerprint[..2], [0x9f, 0x86]);
+        assert_eq!(fingerprint[31], 0x08);
+        assert_eq!(format_fingerprint(&fingerprint).len(), 32 * 3 - 1);
+        assert_eq!(
+            parse_fingerprint(&format_fingerprint(&fingerprint)).unwrap(),
+            fingerprint
+        );
+        assert_eq!(parse_fingerprint(&hex.to_lowercase()).unwrap(), fingerprint);
+
+        assert!(parse_fingerprint(&hex[2..]).is_err());
+        assert!(parse_fingerprint(&format!("{hex}00")).is_err());
+        assert!(parse_fingerprint(&format!("+F{}", &hex[2..])).is_err());
+        assert!(parse_fingerprint(&format!("ZZ{}", &hex[2..])).is_err());
+    }
+}
@@ -0,0 +1,127 @@
+use anyhow::{bail, Context, Result};
+use std::collections::BTreeMap;
+use std::env;
+use std::fmt;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+///
+/// Where a secret such as the password is read from
+///
+#[derive(Debug, Clone)]
+pub enum CredentialSource {
+    /// Environment variable
+    Env(String),
+    /// First line of a file that only its owner may read
+    File(PathBuf),
+    /// First line printed by a shell command, e.g. `pass show mail/work`
+    Command(String),
+    /// Item of the freedesktop Secret Service (GNOME Keyring, KWallet)
+    /// with these attributes, looked up with `secret-tool`
+    SecretService(BTreeMap<String, String>),
+    /// Key of type "user" with this description in the kernel keyring,
+    /// read with `keyctl`
+    Keyring(String),
+}
+
+impl CredentialSource {
+    ///
+    /// Fetch the secret
+    ///
+    pub fn read(&self) -> Result<String> {
+        let secret = match self {
+            CredentialSource::Env(name) => return read_env(name),
+            CredentialSource::File(path) => {
+                check_permissions(path)?;
+                fs::read_to_string(path)
+                    .with_context(|| format!("Failed to read {}", path.display()))?
+            }
+            CredentialSource::Command(command) => run_command("sh", &["-c", command])?,
+            CredentialSource::SecretService(attributes) => {
+                let mut args = vec!["lookup"];
+                for (name, value) in attributes.iter() {
+                    args.push(name);
+                    args.push(value);
+                }
+                run_command("secret-tool", &args)?
+            }
+            CredentialSource::Keyring(description) => {
+                run_command("keyctl", &["pipe", &format!("%user:{description}")])?
+            }
+        };
+
+        match secret.lines().next() {
+            Some(line) if !line.is_empty() => Ok(line.to_string()),
+            _ => bail!("Empty secret from {self}"),
+        }
+    }
+}
+
+impl fmt::Display for CredentialSource {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CredentialSource::Env(name) => write!(f, "env var {name}"),
+            CredentialSource::File(path) => write!(f, "file {}", path.display()),
+            CredentialSource::Command(command) => write!(f, "command {command:?}"),
+            CredentialSource::SecretService(attributes) => {
+                write!(f, "Secret Service item {attributes:?}")
+            }
+            CredentialSource::Keyring(description) => {
+                write!(f, "kernel keyring key {description:?}")
+            }
+        }
+    }
+}
+
+///
+/// Read an environment variable, naming the variable in the error
+///
+pub fn read_env(name: &str) -> Result<String> {
+    env::var(name).with_context(|| format!("Missing or invalid env var: {name}"))
+}
+
+///
+/// Refuse secret files that others can read
+///
+#[cfg(unix)]
+fn check_permissions(path: &Path) -> Result<()> {
+    use std::os::unix::fs::PermissionsExt;
+
+    let metadata =
+        fs::metadata(path).with_context(|| format!("Failed to read {}", path.display()))?;
+    let mode = metadata.permissions().mode();
+    if mode & 0o077 != 0 {
+        bail!(
+            "{} is accessible by group or others (mode {:o}), run `chmod 600` on it",
+            path.display(),
+            mode & 0o777
+        );
+    }
+    Ok(())
+}
+
+#[cfg(not(unix))]
+fn check_permissions(_path: &Path) -> Result<()> {
+    Ok(())
+}
+
+///
+/// Run a program and return what it printed
+///
+pub fn run_command(program: &str, args: &[&str]) -> Result<String> {
+    let description = format!("`{program} {}`", args.join(" "));
+    let output = Command::new(program)
+        .args(args)
+        .output()
+        .with_context(|| format!("Failed to run {description}"))?;
+    if !output.status.success() {
+        bail!(
+            "Command {description} failed with {}: {}",
+            output.status,
+            String::from_utf8_lossy(&output.stderr).trim()
+        );
+    }
+    String::from_utf8(output.stdout)
+        .with_context(|| format!("Command {description} printed invalid UTF-8"))
+}
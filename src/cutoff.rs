@@ -0,0 +1,221 @@
+use crate::date::DateSource;
+use crate::template::Granularity;
+use anyhow::{bail, Context, Result};
+use chrono::{
+    DateTime, Days, LocalResult, Months, NaiveDate, NaiveDateTime, Offset, TimeZone, Utc,
+};
+use chrono_tz::Tz;
+
+///
+/// A minimum age such as `90d`, `12w`, `6m` or `1y`
+///
+#[derive(Debug, Clone, Copy)]
+pub enum Age {
+    Days(u32),
+    Weeks(u32),
+    Months(u32),
+    Years(u32),
+}
+
+impl Age {
+    pub fn parse(text: &str) -> Result<Age> {
+        let text = text.trim();
+        let split = text
+            .find(|c: char| !c.is_ascii_digit())
+            .unwrap_or(text.len());
+        let (number, unit) = text.split_at(split);
+        let number: u32 = number
+            .parse()
+            .with_context(|| format!("Invalid age {text:?}, expected e.g. \"90d\""))?;
+
+        Ok(match unit {
+            "d" => Age::Days(number),
+            "w" => Age::Weeks(number),
+            "m" => Age::Months(number),
+            "y" => Age::Years(number),
+            _ => bail!("Invalid unit in age {text:?}, expected one of d, w, m or y"),
+        })
+    }
+
+    fn before(self, date: NaiveDate) -> Option<NaiveDate> {
+        match self {
+            Age::Days(days) => date.checked_sub_days(Days::new(days.into())),
+            Age::Weeks(weeks) => date.checked_sub_days(Days::new(u64::from(weeks) * 7)),
+            Age::Months(months) => date.checked_sub_months(Months::new(months)),
+            Age::Years(years) => date.checked_sub_months(Months::new(years.checked_mul(12)?)),
+        }
+    }
+}
+
+///
+/// Which messages are old enough to be archived
+///
+#[derive(Debug, Clone, Copy)]
+pub enum Cutoff {
+    /// Everything before the current period of the granularity
+    CurrentPeriod,
+    /// Everything older than the given age
+    OlderThan(Age),
+    /// Everything before the given date
+    Before(NaiveDate),
+}
+
+impl Cutoff {
+    ///
+    /// The instant before which messages are archived, as seen from `now`.
+    /// Days and periods start at midnight in `timezone`.
+    ///
+    pub fn instant(
+        self,
+        granularity: Granularity,
+        timezone: Tz,
+        now: DateTime<Utc>,
+    ) -> Result<DateTime<Utc>> {
+        let now = now.with_timezone(&timezone).naive_local();
+        let today = now.date();
+        let date = match self {
+            Cutoff::CurrentPeriod => Some(granularity.start_of_period(today)),
+            Cutoff::OlderThan(age) => {
+                // Keep the time of day so that the age is exact
+                let Some(date) = age.before(today) else {
+                    bail!("Age {age:?} is out of range");
+                };
+                return Ok(to_utc(date.and_time(now.time()), timezone));
+            }
+            Cutoff::Before(date) => Some(date),
+        };
+        match date.and_then(|date| date.and_hms_opt(0, 0, 0)) {
+            Some(midnight) => Ok(to_utc(midnight, timezone)),
+            None => bail!("Cutoff {self:?} is out of range"),
+        }
+    }
+}
+
+///
+/// The instant of a local time. Of an ambiguous time the earlier one is
+/// used, a time skipped by a DST change is taken as UTC offset by the zone
+/// before the change.
+///
+fn to_utc(local: NaiveDateTime, timezone: Tz) -> DateTime<Utc> {
+    match timezone.from_local_datetime(&local) {
+        LocalResult::Single(time) | LocalResult::Ambiguous(time, _) => time.with_timezone(&Utc),
+        LocalResult::None => {
+            let offset = timezone.offset_from_utc_datetime(&local).fix();
+            (local - offset).and_utc()
+        }
+    }
+}
+
+///
+/// An IMAP SEARCH key that matches at least every message older than the
+/// cutoff according to any of the date sources. BEFORE and SENTBEFORE only
+/// look at the date in the zone of each message, which can be a day off
+/// the UTC date. The day after the cutoff is therefore included as well,
+/// to be filtered by the caller.
+///
+pub fn search_before(cutoff: DateTime<Utc>, sources: &[DateSource]) -> String {
+    let date = cutoff
+        .date_naive()
+        .checked_add_days(Days::new(2))
+        .unwrap_or(NaiveDate::MAX);
+    search_date(sources, "BEFORE", "SENTBEFORE", date)
+}
+
+///
+/// An IMAP SEARCH key that matches at least every message not older than
+/// the cutoff, the counterpart of `search_before`
+///
+pub fn search_since(cutoff: DateTime<Utc>, sources: &[DateSource]) -> String {
+    let date = cutoff.date_naive().pred_opt().unwrap_or(NaiveDate::MIN);
+    search_date(sources, "SINCE", "SENTSINCE", date)
+}
+
+///
+/// Combine the keys of every date source with OR. The Received header
+/// cannot be searched, so it matches everything.
+///
+fn search_date(sources: &[DateSource], internal: &str, header: &str, date: NaiveDate) -> String {
+    let mut keys = Vec::new();
+    for source in sources {
+        let key = match source {
+            DateSource::Internal => internal,
+            DateSource::Header => header,
+            DateSource::Received => return "ALL".to_string(),
+        };
+        let key = format!("{key} {}", date.format("%-d-%b-%Y"));
+        if !keys.contains(&key) {
+            keys.push(key);
+        }
+    }
+    keys.into_iter()
+        .reduce(|a, b| format!("OR {a} {b}"))
+        .unwrap_or_else(|| "ALL".to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn utc(text: &str) -> DateTime<Utc> {
+        DateTime::parse_from_rfc3339(text).unwrap().to_utc()
+    }
+
+    #[test]
+    fn parse_ages() {
+        assert!(matches!(Age::parse("90d"), Ok(Age::Days(90))));
+        assert!(matches!(Age::parse(" 12w "), Ok(Age::Weeks(12))));
+        assert!(matches!(Age::parse("6m"), Ok(Age::Months(6))));
+        assert!(matches!(Age::parse("1y"), Ok(Age::Years(1))));
+        for text in ["", "d", "90", "90x", "90 d", "-1d", "1.5y", "99999999999d"] {
+            assert!(Age::parse(text).is_err(), "{text}");
+        }
+    }
+
+    #[test]
+    fn instants() {
+        let now = utc("2024-05-15T10:30:00Z");
+        let instant = |cutoff: Cutoff, timezone: Tz| {
+            cutoff
+                .instant(Granularity::Month, timezone, now)
+                .unwrap()
+                .to_rfc3339()
+        };
+        assert_eq!(
+            instant(Cutoff::CurrentPeriod, Tz::UTC),
+            "2024-05-01T00:00:00+00:00"
+        );
+        assert_eq!(
+            instant(Cutoff::CurrentPeriod, Tz::Europe__Berlin),
+            "2024-04-30T22:00:00+00:00"
+        );
+        assert_eq!(
+            instant(Cutoff::OlderThan(Age::Months(3)), Tz::UTC),
+            "2024-02-15T10:30:00+00:00"
+        );
+        assert!(Cutoff::OlderThan(Age::Years(u32::MAX))
+            .instant(Granularity::Year, Tz::UTC, now)
+            .is_err());
+    }
+
+    #[test]
+    fn search_keys() {
+        let cutoff = utc("2024-03-01T00:00:00Z");
+        assert_eq!(
+            search_before(cutoff, &[DateSource::Internal]),
+            "BEFORE 3-Mar-2024"
+        );
+        assert_eq!(
+            search_since(cutoff, &[DateSource::Internal]),
+            "SINCE 29-Feb-2024"
+        );
+        assert_eq!(
+            search_before(cutoff, &[DateSource::Header, DateSource::Internal]),
+            "OR SENTBEFORE 3-Mar-2024 BEFORE 3-Mar-2024"
+        );
+        assert_eq!(
+            search_since(cutoff, &[DateSource::Header, DateSource::Received]),
+            "ALL"
+        );
+        assert_eq!(search_before(cutoff, &[]), "ALL");
+    }
+}
@@ -0,0 +1,271 @@
+use crate::header;
+use chrono::{DateTime, FixedOffset, NaiveDate, NaiveTime, Offset, TimeZone};
+use chrono_tz::Tz;
+use clap::ValueEnum;
+use imap::types::Fetch;
+use serde::Deserialize;
+
+///
+/// Where the date of a message is taken from
+///
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize, ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum DateSource {
+    /// INTERNALDATE, when the server received the message. Messages copied
+    /// from another server often all have the day of the migration.
+    Internal,
+    /// Date header, set by the sender
+    Header,
+    /// Earliest Received header, added by the servers on the way
+    Received,
+}
+
+///
+/// What happens to a message none of the date sources has a date for
+///
+#[derive(Debug, Clone, Copy, Default, PartialEq, Deserialize, ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum UndatedPolicy {
+    /// Leave it in place and report it
+    #[default]
+    Skip,
+    /// Fall back to INTERNALDATE, then skip
+    Internal,
+    /// Move it to the folder for undated messages
+    Move,
+}
+
+///
+/// Header fields to fetch for the given date sources
+///
+pub fn header_names(sources: &[DateSource]) -> Vec<&'static str> {
+    let mut names = Vec::new();
+    if sources.contains(&DateSource::Header) {
+        names.push("DATE");
+    }
+    if sources.contains(&DateSource::Received) {
+        names.push("RECEIVED");
+    }
+    names
+}
+
+///
+/// Date of a fetched message from the first of the sources that has one,
+/// given its unfolded `headers`
+///
+pub fn message_date(
+    message: &Fetch,
+    headers: &str,
+    sources: &[DateSource],
+) -> Option<DateTime<FixedOffset>> {
+    sources.iter().find_map(|source| match source {
+        DateSource::Internal => message.internal_date(),
+        DateSource::Header => header::values(headers, "Date").find_map(parse_date),
+        DateSource::Received => header::values(headers, "Received")
+            // The date follows the last semicolon
+            .filter_map(|value| parse_date(value.rsplit(';').next()?))
+            .min(),
+    })
+}
+
+///
+/// The same instant as seen in `timezone`, which decides the period a
+/// message belongs to
+///
+pub fn in_zone(date: DateTime<FixedOffset>, timezone: Tz) -> DateTime<FixedOffset> {
+    let local = date.with_timezone(&timezone);
+    local.with_timezone(&local.offset().fix())
+}
+
+///
+/// Offset of the zone names allowed by RFC 5322, and UTC, in hours
+///
+fn zone_offset(name: &str) -> Option<i32> {
+    Some(match name.to_ascii_uppercase().as_str() {
+        "UT" | "UTC" | "GMT" | "Z" => 0,
+        "EDT" => -4,
+        "EST" | "CDT" => -5,
+        "CST" | "MDT" => -6,
+        "MST" | "PDT" => -7,
+        "PST" => -8,
+        _ => return None,
+    })
+}
+
+///
+/// Seconds east of UTC of a zone like `+0200`, `-05:00` or `+01`
+///
+fn parse_offset(token: &str) -> Option<i32> {
+    let digits: String = token[1..].chars().filter(|c| *c != ':').collect();
+    let hours: i32 = digits.get(..2)?.parse().ok()?;
+    let minutes: i32 = digits.get(2..4).unwrap_or("00").parse().ok()?;
+    let seconds = hours * 3600 + minutes * 60;
+    Some(if token.starts_with('-') {
+        -seconds
+    } else {
+        seconds
+    })
+}
+
+///
+/// Time of day like `13:05:59` or `13:05`, with a leap second clamped
+///
+fn parse_time(token: &str) -> Option<NaiveTime> {
+    let mut parts = token.split(':').map(|part| part.parse::<u32>().ok());
+    let hour = parts.next()??;
+    let minute = parts.next()??;
+    let second = parts.next().unwrap_or(Some(0))?;
+    NaiveTime::from_hms_opt(hour, minute, second.min(59))
+}
+
+///
+/// Parse an RFC 5322 date, also accepting the many malformed variants
+/// found in real messages: missing or misplaced weekday, two digit years,
+/// asctime order, missing seconds or zone, zone names and comments.
+/// Dates without a zone are taken as UTC.
+///
+pub fn parse_date(text: &str) -> Option<DateTime<FixedOffset>> {
+    if let Ok(date) = DateTime::parse_from_rfc2822(text.trim()) {
+        return Some(date);
+    }
+
+    // Drop comments such as "(CEST)"
+    let mut plain = String::new();
+    let mut depth = 0u32;
+    for c in text.chars() {
+        match c {
+            '(' => depth += 1,
+            ')' => depth = depth.saturating_sub(1),
+            _ if depth == 0 => plain.push(c),
+            _ => {}
+        }
+    }
+
+    let (mut day, mut month, mut year, mut time, mut offset) = (None, None, None, None, None);
+    for token in plain.split(|c: char| c.is_whitespace() || c == ',') {
+        if token.is_empty() {
+            continue;
+        }
+        if token.starts_with(['+', '-']) {
+            offset = parse_offset(token).or(offset);
+        } else if token.contains(':') {
+            // An impossible time makes the whole date suspect
+            time = Some(parse_time(token)?);
+        } else if let Ok(number) = token.parse::<i32>() {
+            if token.len() <= 2 && day.is_none() {
+                day = Some(number);
+            } else if year.is_none() {
+                year = Some(match (token.len(), number) {
+                    // Two digit years as interpreted by RFC 5322
+                    (..=2, 0..=49) => number + 2000,
+                    (..=3, _) => number + 1900,
+                    _ => number,
+                });
+            }
+        } else if let Some(hours) = zone_offset(token) {
+            offset = Some(hours * 3600);
+        } else if let (None, Some(prefix)) = (month, token.get(..3)) {
+            let prefix = prefix.to_ascii_lowercase();
+            month = [
+                "jan", "feb", "mar", "apr", "may", "jun", "jul", "aug", "sep", "oct", "nov", "dec",
+            ]
+            .iter()
+            .position(|name| *name == prefix)
+            .map(|index| index as u32 + 1);
+        }
+    }
+
+    let date = NaiveDate::from_ymd_opt(year?, month?, u32::try_from(day?).ok()?)?;
+    let zone = FixedOffset::east_opt(offset.unwrap_or(0))?;
+    zone.from_local_datetime(&date.and_time(time.unwrap_or(NaiveTime::MIN)))
+        .single()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parsed(text: &str) -> Option<String> {
+        parse_date(text).map(|date| date.to_rfc3339())
+    }
+
+    #[test]
+    fn parse_valid_dates() {
+        assert_eq!(
+            parsed("Mon, 3 Jun 2024 13:05:59 +0200").as_deref(),
+            Some("2024-06-03T13:05:59+02:00")
+        );
+        assert_eq!(
+            parsed("3 Jun 2024 13:05:59 -0500").as_deref(),
+            Some("2024-06-03T13:05:59-05:00")
+        );
+    }
+
+    #[test]
+    fn parse_malformed_dates() {
+        for (text, expected) in [
+            // Two and three digit years
+            ("3 Jun 24 13:05:59 +0000", "2024-06-03T13:05:59+00:00"),
+            ("3 Jun 99 13:05:59 +0000", "1999-06-03T13:05:59+00:00"),
+            ("3 Jun 124 13:05:59 +0000", "2024-06-03T13:05:59+00:00"),
+            // asctime order
+            ("Mon Jun  3 13:05:59 2024", "2024-06-03T13:05:59+00:00"),
+            // Misplaced weekday, missing seconds and zone
+            ("3 Jun 2024 Mon 13:05", "2024-06-03T13:05:00+00:00"),
+            ("3 Jun 2024", "2024-06-03T00:00:00+00:00"),
+            // Zone names, other offset formats and comments
+            ("3 Jun 2024 13:05:59 EST", "2024-06-03T13:05:59-05:00"),
+            ("3 Jun 2024 13:05:59 utc", "2024-06-03T13:05:59+00:00"),
+            ("3 Jun 2024 13:05:59 +02:00", "2024-06-03T13:05:59+02:00"),
+            ("3 Jun 2024 13:05:59 +01", "2024-06-03T13:05:59+01:00"),
+            (
+                "Mon, 3 Jun 2024 13:05:59 +0200 (CEST)",
+                "2024-06-03T13:05:59+02:00",
+            ),
+            (
+                "Mon, 3 (x (y)) Jun 2024 13:05:59",
+                "2024-06-03T13:05:59+00:00",
+            ),
+            // Full month names and a leap second
+            ("3 June 2024 23:59:60 +0000", "2024-06-03T23:59:59+00:00"),
+            // A detached sign does not turn the offset into the year
+            ("3 Jun 2024 13:05:59 - 0100", "2024-06-03T13:05:59+00:00"),
+            (
+                "Lun, 3 Jun 2024 13:05:59 +0000 (heure d'été)",
+                "2024-06-03T13:05:59+00:00",
+            ),
+        ] {
+            assert_eq!(parsed(text).as_deref(), Some(expected), "{text}");
+        }
+    }
+
+    #[test]
+    fn parse_rejects_invalid_dates() {
+        for text in [
+            "",
+            "garbage",
+            "Mon, 31 Jun 2024 13:05:59 +0000",
+            "30 Feb 2024",
+            "3 Jun 13:05:59 +0000",
+            "3 2024 13:05:59",
+            "Jun 2024",
+            "3 Jun 2024 25:00:00 +0000",
+            "3 Jun 2024 13:05:59 +9900",
+            "3 Ĵün 2024",
+            "3 Jü 2024",
+        ] {
+            assert_eq!(parsed(text), None, "{text}");
+        }
+    }
+
+    #[test]
+    fn zones() {
+        let date = parse_date("Mon, 31 Dec 2024 23:30:00 +0000").unwrap();
+        let local = in_zone(date, Tz::Europe__Berlin);
+        assert_eq!(local.to_rfc3339(), "2025-01-01T00:30:00+01:00");
+        assert_eq!(
+            header_names(&[DateSource::Received, DateSource::Header]),
+            ["DATE", "RECEIVED"]
+        );
+    }
+}
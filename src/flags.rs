@@ -0,0 +1,156 @@
+use clap::ValueEnum;
+use imap::types::Flag;
+use serde::Deserialize;
+
+///
+/// A system flag, or the lack of `\Seen`, that keeps a message in place
+///
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize, ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum ProtectedFlag {
+    /// `\Flagged`, starred or pinned in most clients
+    Flagged,
+    /// `\Draft`
+    Draft,
+    /// `\Answered`
+    Answered,
+    /// Not read yet
+    Unseen,
+}
+
+impl ProtectedFlag {
+    fn name(self) -> &'static str {
+        match self {
+            ProtectedFlag::Flagged => "\\Flagged",
+            ProtectedFlag::Draft => "\\Draft",
+            ProtectedFlag::Answered => "\\Answered",
+            ProtectedFlag::Unseen => "unseen",
+        }
+    }
+
+    ///
+    /// SEARCH key matching the messages this flag does not protect
+    ///
+    fn search_unset(self) -> &'static str {
+        match self {
+            ProtectedFlag::Flagged => "UNFLAGGED",
+            ProtectedFlag::Draft => "UNDRAFT",
+            ProtectedFlag::Answered => "UNANSWERED",
+            ProtectedFlag::Unseen => "SEEN",
+        }
+    }
+
+    fn is_set(self, flags: &[Flag]) -> bool {
+        match self {
+            ProtectedFlag::Flagged => flags.contains(&Flag::Flagged),
+            ProtectedFlag::Draft => flags.contains(&Flag::Draft),
+            ProtectedFlag::Answered => flags.contains(&Flag::Answered),
+            ProtectedFlag::Unseen => !flags.contains(&Flag::Seen),
+        }
+    }
+}
+
+///
+/// Whether `keyword` can be sent as an IMAP flag keyword, which is an atom
+///
+pub fn is_keyword(keyword: &str) -> bool {
+    !keyword.is_empty()
+        && keyword
+            .chars()
+            .all(|c| c.is_ascii_graphic() && !"()[]{%*\"\\".contains(c))
+}
+
+///
+/// SEARCH keys matching the messages none of the protected flags and
+/// keywords are set on, or None if nothing is protected
+///
+pub fn search_unprotected(
+    protected_flags: &[ProtectedFlag],
+    protected_keywords: &[String],
+) -> Option<String> {
+    let keys: Vec<String> = protected_flags
+        .iter()
+        .map(|flag| flag.search_unset().to_string())
+        .chain(
+            protected_keywords
+                .iter()
+                .map(|keyword| format!("UNKEYWORD {keyword}")),
+        )
+        .collect();
+    (!keys.is_empty()).then(|| keys.join(" "))
+}
+
+///
+/// The first of the protected flags and keywords set on a message, if
+/// any. Keywords are compared case-insensitively like the server does.
+///
+pub fn protected_by(
+    flags: &[Flag],
+    protected_flags: &[ProtectedFlag],
+    protected_keywords: &[String],
+) -> Option<String> {
+    if let Some(flag) = protected_flags.iter().find(|flag| flag.is_set(flags)) {
+        return Some(flag.name().to_string());
+    }
+    protected_keywords
+        .iter()
+        .find(|keyword| {
+            flags.iter().any(|flag| match flag {
+                Flag::Custom(custom) => custom.eq_ignore_ascii_case(keyword),
+                _ => false,
+            })
+        })
+        .cloned()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn keywords() -> Vec<String> {
+        vec!["$Important".to_string(), "$Todo".to_string()]
+    }
+
+    #[test]
+    fn protected_flags_and_keywords() {
+        let protected = [ProtectedFlag::Flagged, ProtectedFlag::Unseen];
+        assert_eq!(protected_by(&[Flag::Seen], &protected, &keywords()), None);
+        assert_eq!(
+            protected_by(&[Flag::Seen, Flag::Flagged], &protected, &keywords()),
+            Some("\\Flagged".to_string())
+        );
+        assert_eq!(
+            protected_by(&[Flag::Answered], &protected, &keywords()),
+            Some("unseen".to_string())
+        );
+        assert_eq!(
+            protected_by(
+                &[Flag::Seen, Flag::Custom("$important".into())],
+                &protected,
+                &keywords()
+            ),
+            Some("$Important".to_string())
+        );
+        assert_eq!(protected_by(&[], &[], &[]), None);
+    }
+
+    #[test]
+    fn keywords_are_atoms() {
+        assert!(is_keyword("$Todo"));
+        assert!(is_keyword("NonJunk"));
+        assert!(!is_keyword(""));
+        assert!(!is_keyword("two words"));
+        assert!(!is_keyword("\\Seen"));
+        assert!(!is_keyword("a(b"));
+        assert!(!is_keyword("wichtig✓"));
+    }
+
+    #[test]
+    fn unprotected_search() {
+        assert_eq!(
+            search_unprotected(&[ProtectedFlag::Draft, ProtectedFlag::Unseen], &keywords()),
+            Some("UNDRAFT SEEN UNKEYWORD $Important UNKEYWORD $Todo".to_string())
+        );
+        assert_eq!(search_unprotected(&[], &[]), None);
+    }
+}
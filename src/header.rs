@@ -0,0 +1,87 @@
+use imap::types::Fetch;
+
+///
+/// Header fields fetched along with a message, with folded lines joined
+///
+pub fn unfold(message: &Fetch) -> String {
+    let Some(header) = message.header() else {
+        return String::new();
+    };
+    let mut unfolded = String::new();
+    for line in String::from_utf8_lossy(header).lines() {
+        if line.starts_with([' ', '\t']) {
+            unfolded.push(' ');
+            unfolded.push_str(line.trim());
+        } else {
+            if !unfolded.is_empty() {
+                unfolded.push('\n');
+            }
+            unfolded.push_str(line);
+        }
+    }
+    unfolded
+}
+
+///
+/// Values of every header field with this name
+///
+pub fn values<'a>(headers: &'a str, name: &'a str) -> impl Iterator<Item = &'a str> {
+    headers.lines().filter_map(move |line| {
+        let (field, value) = line.split_once(':')?;
+        field
+            .trim()
+            .eq_ignore_ascii_case(name)
+            .then(|| value.trim())
+    })
+}
+
+///
+/// Lowercased identifier of the mailing list from the List-Id header
+/// (RFC 2919), e.g. `dev.lists.example.org` for
+/// `Developers <dev.lists.example.org>`. Without the angle brackets the
+/// last word of the value is taken as the identifier.
+///
+pub fn list_id(headers: &str) -> Option<String> {
+    let value = values(headers, "List-Id").next()?;
+    let id = match (value.rfind('<'), value.rfind('>')) {
+        (Some(start), Some(end)) if start < end => value[start + 1..end].trim(),
+        _ => value.split_whitespace().last()?,
+    };
+    (!id.is_empty()).then(|| id.to_lowercase())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn header_values() {
+        let headers = "Subject: Hi\nreceived: from a\nReceived: from b";
+        assert_eq!(
+            values(headers, "Received").collect::<Vec<_>>(),
+            ["from a", "from b"]
+        );
+        assert_eq!(values(headers, "Date").count(), 0);
+    }
+
+    #[test]
+    fn list_ids() {
+        let parse = |value: &str| list_id(&format!("List-Id: {value}"));
+        assert_eq!(
+            parse("Developers <Dev.Lists.Example.org>").as_deref(),
+            Some("dev.lists.example.org")
+        );
+        assert_eq!(
+            parse("<a/b.example.org>").as_deref(),
+            Some("a/b.example.org")
+        );
+        // Without angle brackets the last word is the identifier
+        assert_eq!(
+            parse("Developers dev.lists.example.org").as_deref(),
+            Some("dev.lists.example.org")
+        );
+        assert_eq!(parse("Developers <>"), None);
+        assert_eq!(parse(""), None);
+        assert_eq!(list_id("Subject: Hi"), None);
+    }
+}
@@ -1,154 +1,157 @@
-#[macro_use]
-extern crate lazy_static;
-
-use anyhow::Result;
-use chrono::Datelike;
-use imap::Session;
-use native_tls::TlsStream;
-use std::collections::HashMap;
-use std::env;
-use std::net::TcpStream;
+mod archiver;
+mod auth;
+mod checkpoint;
+mod condstore;
+mod config;
+mod connection;
+mod credentials;
+mod cutoff;
+mod date;
+mod flags;
+mod header;
+mod namespace;
+mod plan;
+mod template;
+mod threads;
+
+use anyhow::{bail, Context, Result};
+use archiver::{Archiver, Summary};
+use checkpoint::Checkpoints;
+use clap::Parser;
+use config::{Account, Config};
+use date::UndatedPolicy;
+use std::collections::BTreeMap;
+use std::fs;
 use std::sync::Mutex;
-
-const MAX_UIDS: usize = 256;
-
-type Uid = u32;
-type Year = u32;
-
-///
-/// Turn a HashSet with UIDs into a comma separated String
-///
-fn create_uidset(uids: &[Uid]) -> String {
-    uids.iter()
-        .map(|uid| uid.to_string())
-        .fold(String::new(), |mut a, b| {
-            if !a.is_empty() {
-                a.push(',');
-            }
-            a.push_str(&b);
-            a
-        })
-}
-
-fn year_to_folder(year: Year) -> String {
-    String::from("Archives/") + &year.to_string()
-}
-
-lazy_static! {
-    static ref EXISTING_YEARS: Mutex<Vec<Year>> = Mutex::new(Vec::new());
-}
-
-///
-/// Ensure that a mail folder exists for the given year. Checks first
-/// if the folder already exists and caches the result so that at the most
-/// the server will see one LIST and one CREATE per folder.
-///
-fn create_folder(year: &Year, session: &mut Session<TlsStream<TcpStream>>) -> Result<()> {
-    let mut cached = EXISTING_YEARS.lock().unwrap();
-    if cached.contains(year) {
-        // We have already tested/created this year
-        return Ok(());
-    }
-
-    let folder_name = year_to_folder(*year);
-    let folders = session.list(None, Some(&folder_name))?;
-    assert!(folders.len() < 2);
-
-    if !folders.is_empty() {
-        println!("Caching existing folder for year {year}");
-        cached.push(*year);
-        return Ok(());
-    }
-
-    println!("Creating missing folder for year {year}");
-    session.create(folder_name)?;
-    cached.push(*year);
-    Ok(())
-}
-
-///
-/// Move given set of messages to a folder based on the passed year
-/// 
-fn archive_messages(
-    year: Year,
-    uids: &[Uid],
-    session: &mut Session<TlsStream<TcpStream>>,
-) -> Result<()> {
-    let uidset = create_uidset(uids);
-    let folder_name = year_to_folder(year);
-
-    session.uid_mv(uidset, folder_name)?;
-    Ok(())
+use std::thread;
+
+fn run_account(
+    account: &Account,
+    dry_run: bool,
+    checkpoints: Option<&Checkpoints>,
+) -> Result<Summary> {
+    Archiver::connect(account, dry_run, checkpoints)?.run()
 }
 
 ///
-/// Take a batch of messages and archive them
+/// Process the accounts using at most `concurrency` parallel connections.
+/// Results are returned in the same order as the accounts.
 ///
-fn process_messages(uids: Vec<Uid>, session: &mut Session<TlsStream<TcpStream>>) -> Result<()> {
-    println!("Processing {} messages", uids.len());
-    let uidset = create_uidset(&uids);
-    let messages = session.uid_fetch(uidset, "(UID INTERNALDATE)")?;
-
-    let mut years = HashMap::<Year, Vec<Uid>>::new();
-    for message in messages.iter() {
-        let year = message
-            .internal_date()
-            .expect("Message has no date")
-            .format("%Y")
-            .to_string()
-            .parse::<Year>()?;
-
-        if year == chrono::Utc::now().year().try_into()? {
-            continue;
+fn run_accounts(config: &Config, checkpoints: Option<&Checkpoints>) -> Vec<Result<Summary>> {
+    let queue = Mutex::new(config.accounts.iter().enumerate());
+    let results = Mutex::new(Vec::new());
+
+    thread::scope(|scope| {
+        for _ in 0..config.concurrency.min(config.accounts.len()) {
+            scope.spawn(|| loop {
+                let next = queue.lock().unwrap().next();
+                let Some((index, account)) = next else {
+                    break;
+                };
+                let result = run_account(account, config.dry_run, checkpoints);
+                if let Err(e) = &result {
+                    eprintln!("[{}] Failed: {e:#}", account.name);
+                }
+                results.lock().unwrap().push((index, result));
+            });
         }
+    });
 
-        years.entry(year).or_insert(Vec::new());
-        years
-            .get_mut(&year)
-            .expect("Year missing from HashMap")
-            .push(message.uid.expect("Message has no UID"));
-    }
-
-    for year in years.keys() {
-        create_folder(year, session)?;
-        archive_messages(*year, &years[year], session)?;
-    }
-
-    Ok(())
+    let mut results = results.into_inner().unwrap();
+    results.sort_by_key(|(index, _)| *index);
+    results.into_iter().map(|(_, result)| result).collect()
 }
 
 fn main() -> Result<()> {
-    let args: Vec<String> = env::args().collect();
-    let server = args[1].clone();
-    let server: &str = server.as_str();
-
-    let username = env::var("IMAP_USERNAME").expect("Missing or invalid env var: IMAP_USERNAME");
-    let password = env::var("IMAP_PASSWORD").expect("Missing or invalid env var: IMAP_PASSWORD");
-
-    let tls = native_tls::TlsConnector::builder().build()?;
-    let client = imap::connect_starttls((server, 143), server, &tls)?;
-
-    let mut session = client.login(username, password).expect("Failed IMAP login");
-
-    let capabilities = session.capabilities()?;
-    assert!(capabilities.has_str("MOVE"));
-
-    let mailbox = session.select("INBOX")?;
-    assert!(mailbox.uid_validity.is_some());
-
-    let uids = session.uid_search("ALL")?;
-
-    let mut batch: Vec<Uid> = Vec::new();
-    for uid in uids.iter() {
-        batch.push(*uid);
-        if batch.len() == MAX_UIDS {
-            process_messages(batch, &mut session)?;
-            batch = Vec::new();
+    let config = config::load(config::Args::parse())?;
+    let checkpoints = match &config.state_file {
+        Some(path) => Some(Checkpoints::load(path, config.full_scan)?),
+        None => None,
+    };
+    let results = run_accounts(&config, checkpoints.as_ref());
+
+    let (moved, created) = if config.dry_run {
+        ("would move", "would create")
+    } else {
+        ("moved", "created")
+    };
+
+    println!("Summary:");
+    let mut failed = 0;
+    let mut plans = BTreeMap::new();
+    for (account, result) in config.accounts.iter().zip(results.iter()) {
+        match result {
+            Ok(summary) => {
+                println!(
+                    "  {}: {moved} {} messages from {} mailboxes, {created} {} folders",
+                    account.name,
+                    summary.messages_moved,
+                    summary.mailboxes,
+                    summary.folders_created
+                );
+                if summary.messages_undated > 0 {
+                    let outcome = if account.undated == UndatedPolicy::Move {
+                        format!("{moved} to {}", account.undated_folder)
+                    } else {
+                        "left in place".to_string()
+                    };
+                    println!(
+                        "    {} messages without a date {outcome}",
+                        summary.messages_undated
+                    );
+                }
+                if !summary.messages_protected.is_empty() {
+                    let counts: Vec<String> = summary
+                        .messages_protected
+                        .iter()
+                        .map(|(reason, count)| format!("{count} {reason}"))
+                        .collect();
+                    println!(
+                        "    {} protected messages left in place: {}",
+                        summary.messages_protected.values().sum::<usize>(),
+                        counts.join(", ")
+                    );
+                }
+                if summary.messages_held_by_thread > 0 {
+                    println!(
+                        "    {} messages left with a thread that stays",
+                        summary.messages_held_by_thread
+                    );
+                }
+                if summary.messages_kept > 0 {
+                    println!("    {} messages kept by rules", summary.messages_kept);
+                }
+                if summary.messages_deleted > 0 {
+                    println!(
+                        "    {} messages already marked as deleted left in place",
+                        summary.messages_deleted
+                    );
+                }
+                if summary.messages_without_uid > 0 {
+                    println!(
+                        "    {} FETCH responses without a UID ignored",
+                        summary.messages_without_uid
+                    );
+                }
+                if let Some(plan) = &summary.plan {
+                    plan.print();
+                    plans.insert(&account.name, plan);
+                }
+            }
+            Err(e) => {
+                failed += 1;
+                println!("  {}: FAILED: {e:#}", account.name);
+            }
         }
     }
-    if !batch.is_empty() {
-        process_messages(batch, &mut session)?;
+
+    if let Some(path) = &config.plan_file {
+        fs::write(path, serde_json::to_string_pretty(&plans)?)
+            .with_context(|| format!("Failed to write plan to {}", path.display()))?;
     }
 
+    if failed > 0 {
+        bail!("{failed} of {} accounts failed", config.accounts.len());
+    }
     Ok(())
 }
@@ -0,0 +1,265 @@
+use crate::archiver::ImapSession;
+use anyhow::Result;
+
+///
+/// Quote a string for use as an IMAP mailbox name or pattern
+///
+pub fn quote(value: &str) -> String {
+    format!("\"{}\"", value.replace('\\', "\\\\").replace('"', "\\\""))
+}
+
+///
+/// Alphabet of the modified BASE64 used in mailbox names
+///
+const BASE64: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+,";
+
+///
+/// Encode a mailbox name in the modified UTF-7 of RFC 3501 (section
+/// 5.1.3): `&` becomes `&-`, and runs of characters other than printable
+/// ASCII become their UTF-16 in modified BASE64 between `&` and `-`
+///
+fn encode_utf7(name: &str) -> String {
+    let mut encoded = String::new();
+    let mut units = Vec::new();
+    for c in name.chars().map(Some).chain([None]) {
+        if let Some(c) = c.filter(|c| !matches!(c, ' '..='~')) {
+            units.extend_from_slice(c.encode_utf16(&mut [0; 2]));
+            continue;
+        }
+        if !units.is_empty() {
+            let bytes: Vec<u8> = units.drain(..).flat_map(u16::to_be_bytes).collect();
+            encoded.push('&');
+            for group in bytes.chunks(3) {
+                let bits = group.iter().enumerate().fold(0u32, |bits, (i, &byte)| {
+                    bits | (u32::from(byte) << (16 - 8 * i))
+                });
+                for i in 0..=group.len() {
+                    encoded.push(BASE64[((bits >> (18 - 6 * i)) & 63) as usize] as char);
+                }
+            }
+            encoded.push('-');
+        }
+        match c {
+            Some('&') => encoded.push_str("&-"),
+            Some(c) => encoded.push(c),
+            None => {}
+        }
+    }
+    encoded
+}
+
+///
+/// Decode a mailbox name in modified UTF-7, or None if it is not valid
+///
+fn decode_utf7(name: &str) -> Option<String> {
+    let mut decoded = String::new();
+    let mut rest = name;
+    while let Some(start) = rest.find('&') {
+        decoded.push_str(&rest[..start]);
+        let (shifted, tail) = rest[start + 1..].split_once('-')?;
+        if shifted.is_empty() {
+            decoded.push('&');
+        } else {
+            let mut bits = 0u32;
+            let mut count = 0;
+            let mut bytes = Vec::new();
+            for c in shifted.bytes() {
+                let value = BASE64.iter().position(|&b| b == c)? as u32;
+                bits = bits << 6 | value;
+                count += 6;
+                if count >= 8 {
+                    count -= 8;
+                    bytes.push((bits >> count) as u8);
+                    bits &= (1 << count) - 1;
+                }
+            }
+            let units: Vec<u16> = bytes
+                .chunks_exact(2)
+                .map(|pair| u16::from_be_bytes([pair[0], pair[1]]))
+                .collect();
+            if bytes.len() % 2 != 0 {
+                return None;
+            }
+            decoded.push_str(&String::from_utf16(&units).ok()?);
+        }
+        rest = tail;
+    }
+    decoded.push_str(rest);
+    Some(decoded)
+}
+
+///
+/// How folder paths are mapped to mailbox names on the server.
+///
+/// Destination templates always use `/` to separate hierarchy levels.
+/// The path is translated to the delimiter the server reports and placed
+/// under the personal namespace, so that `Archives/2023` becomes
+/// `INBOX.Archives.2023` on a Courier or Cyrus server.
+///
+#[derive(Debug, Clone)]
+pub struct Namespace {
+    prefix: String,
+    delimiter: Option<char>,
+}
+
+impl Namespace {
+    ///
+    /// Ask the server for its hierarchy delimiter and find the prefix of
+    /// the personal namespace. NAMESPACE (RFC 2342) would report it, but
+    /// the imap crate cannot parse its response and the connection is out
+    /// of step afterwards. Servers that keep every folder below INBOX, like
+    /// Courier and Cyrus, are instead recognised by having folders there
+    /// and none besides INBOX at the top level. A configured
+    /// `forced_prefix` replaces the detected one.
+    ///
+    pub fn discover(session: &mut ImapSession, forced_prefix: Option<&str>) -> Result<Namespace> {
+        // LIST with an empty mailbox name returns just the delimiter
+        let names = session.list(None, None)?;
+        let delimiter = names
+            .first()
+            .and_then(|name| name.delimiter())
+            .and_then(|d| d.chars().next());
+
+        let prefix = match (forced_prefix, delimiter) {
+            (Some(prefix), _) => prefix.to_string(),
+            (None, Some(delimiter)) => {
+                let inbox = format!("INBOX{delimiter}");
+                let top_level = session.list(None, Some(&quote("%")))?;
+                let only_inbox = top_level
+                    .iter()
+                    .all(|name| name.name().eq_ignore_ascii_case("INBOX"));
+                let below_inbox = session.list(None, Some(&quote(&format!("{inbox}%"))))?;
+                if only_inbox && !below_inbox.is_empty() {
+                    inbox
+                } else {
+                    String::new()
+                }
+            }
+            (None, None) => String::new(),
+        };
+
+        Ok(Namespace { prefix, delimiter })
+    }
+
+    pub fn delimiter(&self) -> Option<char> {
+        self.delimiter
+    }
+
+    ///
+    /// Turn a `/` separated folder path into a mailbox name on the server,
+    /// encoded in modified UTF-7
+    ///
+    pub fn to_mailbox(&self, path: &str) -> String {
+        let name = encode_utf7(&match self.delimiter {
+            Some(delimiter) => path.replace('/', &delimiter.to_string()),
+            None => path.to_string(),
+        });
+        if name.starts_with(&self.prefix) {
+            name
+        } else {
+            format!("{}{name}", self.prefix)
+        }
+    }
+
+    ///
+    /// Turn a mailbox name on the server into a `/` separated folder path,
+    /// dropping the personal namespace prefix and decoding modified UTF-7
+    ///
+    pub fn to_path(&self, mailbox: &str) -> String {
+        let name = mailbox.strip_prefix(&self.prefix).unwrap_or(mailbox);
+        let name = decode_utf7(name).unwrap_or_else(|| name.to_string());
+        match self.delimiter {
+            Some(delimiter) => name.replace(delimiter, "/"),
+            None => name.to_string(),
+        }
+    }
+
+    ///
+    /// LIST pattern that matches every mailbox below the given one, or
+    /// None if the server has no hierarchy
+    ///
+    pub fn children_pattern(&self, mailbox: &str) -> Option<String> {
+        self.delimiter
+            .map(|delimiter| format!("{mailbox}{delimiter}*"))
+    }
+
+    ///
+    /// True if `mailbox` is `parent` or one of its descendants
+    ///
+    pub fn contains(&self, parent: &str, mailbox: &str) -> bool {
+        match (mailbox.strip_prefix(parent), self.delimiter) {
+            (Some(""), _) => true,
+            (Some(rest), Some(delimiter)) => rest.starts_with(delimiter),
+            _ => false,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn namespace(prefix: &str, delimiter: Option<char>) -> Namespace {
+        Namespace {
+            prefix: prefix.to_string(),
+            delimiter,
+        }
+    }
+
+    #[test]
+    fn quote_escapes() {
+        assert_eq!(quote("Old Mail"), "\"Old Mail\"");
+        assert_eq!(quote("a\"b\\c"), "\"a\\\"b\\\\c\"");
+    }
+
+    #[test]
+    fn mailbox_names() {
+        let flat = namespace("", Some('/'));
+        assert_eq!(flat.to_mailbox("Archives/2023"), "Archives/2023");
+        assert_eq!(flat.to_path("Archives/2023"), "Archives/2023");
+
+        let courier = namespace("INBOX.", Some('.'));
+        assert_eq!(courier.to_mailbox("Archives/2023"), "INBOX.Archives.2023");
+        assert_eq!(courier.to_mailbox("INBOX.Sent"), "INBOX.Sent");
+        assert_eq!(courier.to_path("INBOX.Archives.2023"), "Archives/2023");
+        assert_eq!(courier.to_path("INBOX"), "INBOX");
+
+        let no_hierarchy = namespace("", None);
+        assert_eq!(no_hierarchy.to_mailbox("Archives/2023"), "Archives/2023");
+        assert_eq!(no_hierarchy.children_pattern("INBOX"), None);
+    }
+
+    #[test]
+    fn modified_utf7() {
+        let flat = namespace("", Some('/'));
+        assert_eq!(flat.to_mailbox("Archives/R&D"), "Archives/R&-D");
+        assert_eq!(flat.to_mailbox("Entwürfe/2023"), "Entw&APw-rfe/2023");
+        assert_eq!(flat.to_mailbox("台北"), "&U,BTFw-");
+        assert_eq!(
+            flat.to_mailbox("~peter/mail/日本語"),
+            "~peter/mail/&ZeVnLIqe-"
+        );
+        assert_eq!(flat.to_mailbox("😀"), "&2D3eAA-");
+
+        assert_eq!(flat.to_path("Archives/R&-D"), "Archives/R&D");
+        assert_eq!(flat.to_path("Entw&APw-rfe/2023"), "Entwürfe/2023");
+        assert_eq!(flat.to_path("&U,BTFw-"), "台北");
+        assert_eq!(flat.to_path("&2D3eAA-"), "😀");
+        assert_eq!(flat.to_path("R&D"), "R&D");
+        assert_eq!(flat.to_path("R&*-D"), "R&*-D");
+    }
+
+    #[test]
+    fn children_and_containment() {
+        let courier = namespace("INBOX.", Some('.'));
+        assert_eq!(
+            courier.children_pattern("INBOX.Projects").as_deref(),
+            Some("INBOX.Projects.*")
+        );
+        assert!(courier.contains("INBOX.Archives", "INBOX.Archives"));
+        assert!(courier.contains("INBOX.Archives", "INBOX.Archives.2023"));
+        assert!(!courier.contains("INBOX.Archives", "INBOX.Archives2"));
+        assert!(!courier.contains("INBOX.Archives", "INBOX"));
+        assert_eq!(courier.delimiter(), Some('.'));
+    }
+}
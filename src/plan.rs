@@ -0,0 +1,123 @@
+use chrono::{DateTime, FixedOffset};
+use serde::Serialize;
+use std::collections::BTreeMap;
+
+///
+/// Messages that would be moved from one source mailbox to one
+/// destination folder. Messages without a date only add to the count.
+///
+#[derive(Debug, Serialize)]
+pub struct PlannedMove {
+    pub messages: usize,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub oldest: Option<DateTime<FixedOffset>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub newest: Option<DateTime<FixedOffset>>,
+}
+
+///
+/// Everything a dry run would have changed on one account
+///
+#[derive(Debug, Default, Serialize)]
+pub struct Plan {
+    pub folders_to_create: Vec<String>,
+    /// Source mailbox -> destination folder -> messages
+    pub moves: BTreeMap<String, BTreeMap<String, PlannedMove>>,
+}
+
+impl Plan {
+    ///
+    /// Record that the given messages would be moved
+    ///
+    pub fn add_move(
+        &mut self,
+        source: &str,
+        destination: &str,
+        dates: &[Option<DateTime<FixedOffset>>],
+    ) {
+        if dates.is_empty() {
+            return;
+        }
+
+        let planned = self
+            .moves
+            .entry(source.to_string())
+            .or_default()
+            .entry(destination.to_string())
+            .or_insert(PlannedMove {
+                messages: 0,
+                oldest: None,
+                newest: None,
+            });
+        planned.messages += dates.len();
+        let dates = dates.iter().flatten().copied();
+        planned.oldest = planned.oldest.into_iter().chain(dates.clone()).min();
+        planned.newest = planned.newest.into_iter().chain(dates).max();
+    }
+
+    ///
+    /// Print the plan in a human readable form
+    ///
+    pub fn print(&self) {
+        for folder in self.folders_to_create.iter() {
+            println!("    create {folder}");
+        }
+        for (source, destinations) in self.moves.iter() {
+            for (destination, planned) in destinations.iter() {
+                let dates = match (planned.oldest, planned.newest) {
+                    (Some(oldest), Some(newest)) => format!(
+                        "{} .. {}",
+                        oldest.format("%Y-%m-%d"),
+                        newest.format("%Y-%m-%d")
+                    ),
+                    _ => "undated".to_string(),
+                };
+                println!(
+                    "    move {} messages {source} -> {destination} ({dates})",
+                    planned.messages
+                );
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn date(text: &str) -> Option<DateTime<FixedOffset>> {
+        Some(DateTime::parse_from_rfc3339(text).unwrap())
+    }
+
+    #[test]
+    fn moves_add_up() {
+        let mut plan = Plan::default();
+        plan.add_move("INBOX", "Archives/2022", &[]);
+        assert!(plan.moves.is_empty());
+
+        plan.add_move(
+            "INBOX",
+            "Archives/2022",
+            &[date("2022-03-01T10:00:00+01:00"), None],
+        );
+        plan.add_move(
+            "INBOX",
+            "Archives/2022",
+            &[
+                date("2022-01-15T08:00:00Z"),
+                date("2022-11-30T23:00:00-05:00"),
+            ],
+        );
+        plan.add_move("Sent", "Archives/Undated", &[None]);
+
+        let planned = &plan.moves["INBOX"]["Archives/2022"];
+        assert_eq!(planned.messages, 4);
+        assert_eq!(planned.oldest, date("2022-01-15T08:00:00Z"));
+        assert_eq!(planned.newest, date("2022-11-30T23:00:00-05:00"));
+
+        let undated = &plan.moves["Sent"]["Archives/Undated"];
+        assert_eq!(undated.messages, 1);
+        assert_eq!(undated.oldest, None);
+        assert_eq!(undated.newest, None);
+    }
+}
@@ -0,0 +1,469 @@
+use anyhow::{bail, Result};
+use chrono::{DateTime, Datelike, Days, FixedOffset, NaiveDate};
+use clap::ValueEnum;
+use serde::Deserialize;
+use std::fmt;
+
+///
+/// Values a placeholder in a destination template can refer to
+///
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Field {
+    Year,
+    Month,
+    Quarter,
+    Week,
+    IsoYear,
+    Source,
+    Subfolder,
+    SenderDomain,
+    ListId,
+}
+
+impl Field {
+    fn parse(name: &str) -> Option<Field> {
+        match name {
+            "year" => Some(Field::Year),
+            "month" => Some(Field::Month),
+            "quarter" => Some(Field::Quarter),
+            "week" => Some(Field::Week),
+            "iso_year" => Some(Field::IsoYear),
+            "source" => Some(Field::Source),
+            "subfolder" => Some(Field::Subfolder),
+            "sender_domain" => Some(Field::SenderDomain),
+            "list_id" => Some(Field::ListId),
+            _ => None,
+        }
+    }
+
+    fn is_numeric(self) -> bool {
+        !matches!(
+            self,
+            Field::Source | Field::Subfolder | Field::SenderDomain | Field::ListId
+        )
+    }
+}
+
+///
+/// Length of the period messages are grouped into
+///
+#[derive(Debug, Clone, Copy, Default, PartialEq, Deserialize, ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum Granularity {
+    #[default]
+    Year,
+    Quarter,
+    Month,
+    Week,
+}
+
+impl Granularity {
+    ///
+    /// Destination used when none is configured
+    ///
+    pub fn default_template(self) -> &'static str {
+        match self {
+            Granularity::Year => "Archives/{year}",
+            Granularity::Quarter => "Archives/{year}/Q{quarter}",
+            Granularity::Month => "Archives/{year}/{month:02}",
+            Granularity::Week => "Archives/{iso_year}/W{week:02}",
+        }
+    }
+
+    ///
+    /// First day of the period the date falls into
+    ///
+    pub fn start_of_period(self, date: NaiveDate) -> NaiveDate {
+        let first_of_month =
+            |month0: u32| NaiveDate::from_ymd_opt(date.year(), month0 + 1, 1).expect("Valid month");
+        match self {
+            Granularity::Year => first_of_month(0),
+            Granularity::Quarter => first_of_month(date.month0() / 3 * 3),
+            Granularity::Month => first_of_month(date.month0()),
+            Granularity::Week => date - Days::new(date.weekday().num_days_from_monday().into()),
+        }
+    }
+
+    ///
+    /// Destination used when none is configured for a recursive or wildcard
+    /// source, which keeps the path of each mailbox
+    ///
+    pub fn default_nested_template(self) -> &'static str {
+        match self {
+            Granularity::Year => "Archives/{source}/{year}",
+            Granularity::Quarter => "Archives/{source}/{year}/Q{quarter}",
+            Granularity::Month => "Archives/{source}/{year}/{month:02}",
+            Granularity::Week => "Archives/{source}/{iso_year}/W{week:02}",
+        }
+    }
+
+    ///
+    /// Combinations of placeholders of which the template must contain at
+    /// least one so that different periods end up in different folders. A
+    /// week belongs to its ISO year, which is not always the calendar year.
+    ///
+    fn fields(self) -> &'static [&'static [Field]] {
+        match self {
+            Granularity::Year => &[&[Field::Year], &[Field::IsoYear]],
+            Granularity::Quarter => &[&[Field::Year, Field::Quarter], &[Field::Year, Field::Month]],
+            Granularity::Month => &[&[Field::Year, Field::Month]],
+            Granularity::Week => &[&[Field::IsoYear, Field::Week]],
+        }
+    }
+}
+
+impl fmt::Display for Granularity {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.to_possible_value().unwrap().get_name())
+    }
+}
+
+///
+/// A placeholder value taken from the message as a single folder level.
+/// `/` and the hierarchy `delimiter` of the server would add a level, and
+/// the LIST wildcards `*` and `%` would make the folder match others, so
+/// they become `_` like control characters.
+///
+fn folder_level(value: &str, delimiter: Option<char>) -> String {
+    value
+        .chars()
+        .map(|c| match c {
+            '/' | '*' | '%' => '_',
+            c if c.is_control() || Some(c) == delimiter => '_',
+            c => c,
+        })
+        .collect()
+}
+
+#[derive(Debug, Clone)]
+enum Part {
+    Literal(String),
+    Field { field: Field, width: usize },
+}
+
+///
+/// Everything known about a message that a template can be rendered from
+///
+pub struct Fields<'a> {
+    pub date: DateTime<FixedOffset>,
+    pub source: &'a str,
+    pub subfolder: &'a str,
+    pub sender_domain: Option<&'a str>,
+    pub list_id: Option<&'a str>,
+}
+
+///
+/// A destination folder template such as `Archive/{year}/{month:02}`.
+///
+/// Placeholders are `{year}`, `{month}`, `{quarter}`, `{week}` (ISO week),
+/// `{iso_year}` (the year the ISO week belongs to), `{source}` (the source
+/// mailbox), `{subfolder}` (path of the mailbox below the configured source,
+/// empty for the source itself), `{sender_domain}` and `{list_id}` (the
+/// mailing list from the List-Id header), the last two each rendered as a
+/// single folder level. Numeric placeholders can be zero padded with a
+/// width, e.g. `{week:02}`. Empty folder levels are dropped from the result.
+///
+#[derive(Debug, Clone)]
+pub struct Template {
+    text: String,
+    parts: Vec<Part>,
+}
+
+impl Template {
+    pub fn parse(text: &str) -> Result<Template> {
+        if text.contains(['*', '%']) {
+            bail!("Template {text:?} contains a LIST wildcard ('*' or '%')");
+        }
+        let mut parts = Vec::new();
+        let mut rest = text;
+        while let Some(start) = rest.find('{') {
+            if start > 0 {
+                parts.push(Part::Literal(rest[..start].to_string()));
+            }
+            let Some(end) = rest[start..].find('}') else {
+                bail!("Unterminated placeholder in template {text:?}");
+            };
+            let placeholder = &rest[start + 1..start + end];
+            parts.push(Self::parse_placeholder(placeholder, text)?);
+            rest = &rest[start + end + 1..];
+        }
+        if rest.contains('}') {
+            bail!("Unmatched '}}' in template {text:?}");
+        }
+        if !rest.is_empty() {
+            parts.push(Part::Literal(rest.to_string()));
+        }
+
+        Ok(Template {
+            text: text.to_string(),
+            parts,
+        })
+    }
+
+    fn parse_placeholder(placeholder: &str, text: &str) -> Result<Part> {
+        let (name, spec) = match placeholder.split_once(':') {
+            Some((name, spec)) => (name, Some(spec)),
+            None => (placeholder, None),
+        };
+        let Some(field) = Field::parse(name) else {
+            bail!("Unknown placeholder {{{name}}} in template {text:?}");
+        };
+
+        let width = match spec {
+            None => 0,
+            Some(spec) => {
+                if !field.is_numeric() {
+                    bail!("Placeholder {{{name}}} does not take a width in template {text:?}");
+                }
+                match spec.strip_prefix('0').map(str::parse::<usize>) {
+                    Some(Ok(width)) => width,
+                    _ => bail!(
+                        "Invalid width {spec:?} for {{{name}}} in template {text:?}, \
+                         expected e.g. {{{name}:02}}"
+                    ),
+                }
+            }
+        };
+
+        Ok(Part::Field { field, width })
+    }
+
+    fn uses(&self, wanted: Field) -> bool {
+        self.parts
+            .iter()
+            .any(|part| matches!(part, Part::Field { field, .. } if *field == wanted))
+    }
+
+    ///
+    /// True if the folder depends on the mailbox the message is in
+    ///
+    pub fn uses_mailbox(&self) -> bool {
+        self.uses(Field::Source) || self.uses(Field::Subfolder)
+    }
+
+    ///
+    /// True if rendering needs the domain of the sender
+    ///
+    pub fn uses_sender_domain(&self) -> bool {
+        self.uses(Field::SenderDomain)
+    }
+
+    ///
+    /// True if rendering needs the List-Id header
+    ///
+    pub fn uses_list_id(&self) -> bool {
+        self.uses(Field::ListId)
+    }
+
+    ///
+    /// The folders at the start of the template that do not depend on the
+    /// message, e.g. `Archives` for `Archives/{year}`
+    ///
+    pub fn static_prefix(&self) -> &str {
+        match self.parts.first() {
+            Some(Part::Literal(text)) if self.parts.len() == 1 => text.trim_end_matches('/'),
+            Some(Part::Literal(text)) => text.rfind('/').map_or("", |end| &text[..end]),
+            _ => "",
+        }
+    }
+
+    ///
+    /// Check that messages from different periods of the given
+    /// granularity end up in different folders
+    ///
+    pub fn check_granularity(&self, granularity: Granularity) -> Result<()> {
+        let separates = granularity
+            .fields()
+            .iter()
+            .any(|fields| fields.iter().all(|field| self.uses(*field)));
+        if !separates {
+            bail!(
+                "Template {:?} does not separate messages by {granularity}, \
+                 e.g. {:?} would",
+                self.text,
+                granularity.default_template()
+            );
+        }
+        Ok(())
+    }
+
+    ///
+    /// The `/` separated folder path for a message, on a server with the
+    /// given hierarchy delimiter
+    ///
+    pub fn render(&self, fields: &Fields, delimiter: Option<char>) -> String {
+        let mut folder = String::new();
+        for part in self.parts.iter() {
+            match part {
+                Part::Literal(text) => folder.push_str(text),
+                Part::Field { field, width } => {
+                    let date = fields.date;
+                    let number = match field {
+                        Field::Year => date.year(),
+                        Field::Month => date.month() as i32,
+                        Field::Quarter => (date.month0() / 3 + 1) as i32,
+                        Field::Week => date.iso_week().week() as i32,
+                        Field::IsoYear => date.iso_week().year(),
+                        Field::Source => {
+                            folder.push_str(fields.source);
+                            continue;
+                        }
+                        Field::Subfolder => {
+                            folder.push_str(fields.subfolder);
+                            continue;
+                        }
+                        Field::SenderDomain => {
+                            let domain = fields.sender_domain.unwrap_or("unknown");
+                            folder.push_str(&folder_level(domain, delimiter));
+                            continue;
+                        }
+                        Field::ListId => {
+                            let id = fields.list_id.unwrap_or("unknown");
+                            folder.push_str(&folder_level(id, delimiter));
+                            continue;
+                        }
+                    };
+                    folder.push_str(&format!("{number:0width$}"));
+                }
+            }
+        }
+        // An empty {subfolder} or {source} must not leave an empty level
+        folder
+            .split('/')
+            .filter(|level| !level.is_empty())
+            .collect::<Vec<_>>()
+            .join("/")
+    }
+}
+
+impl fmt::Display for Template {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.text)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn fields<'a>(date: &str) -> Fields<'a> {
+        Fields {
+            date: DateTime::parse_from_rfc3339(date).unwrap(),
+            source: "INBOX",
+            subfolder: "",
+            sender_domain: Some("example.com"),
+            list_id: Some("dev.lists.example.org"),
+        }
+    }
+
+    #[test]
+    fn parse_rejects_invalid_templates() {
+        for text in [
+            "Archives/{year",
+            "Archives/year}",
+            "Archives/{decade}",
+            "Archives/{source:02}",
+            "Archives/{month:2}",
+            "Archives/{month:0x}",
+            "Archives/*/{year}",
+            "Archives/%/{year}",
+        ] {
+            assert!(Template::parse(text).is_err(), "{text}");
+        }
+    }
+
+    #[test]
+    fn render_dates() {
+        let fields = fields("2024-12-30T10:00:00+01:00");
+        let render = |text: &str| Template::parse(text).unwrap().render(&fields, Some('/'));
+        assert_eq!(render("Archives/{year}"), "Archives/2024");
+        assert_eq!(render("Archives/{year}/{month:02}"), "Archives/2024/12");
+        assert_eq!(render("Archives/{year}/Q{quarter}"), "Archives/2024/Q4");
+        // 30 December 2024 is in the first ISO week of 2025
+        assert_eq!(
+            render("Archives/{iso_year}/W{week:02}"),
+            "Archives/2025/W01"
+        );
+    }
+
+    #[test]
+    fn render_drops_empty_levels() {
+        let fields = fields("2024-01-01T00:00:00Z");
+        let template = Template::parse("Archives/{subfolder}/{year}").unwrap();
+        assert_eq!(template.render(&fields, Some('/')), "Archives/2024");
+        let fields = Fields {
+            subfolder: "Projects/A",
+            ..fields
+        };
+        assert_eq!(
+            template.render(&fields, Some('/')),
+            "Archives/Projects/A/2024"
+        );
+    }
+
+    #[test]
+    fn render_keeps_message_values_to_one_level() {
+        let fields = Fields {
+            sender_domain: Some("a/b.c*d%e\r\nf"),
+            list_id: Some("dev.lists.example.org"),
+            ..fields("2024-01-01T00:00:00Z")
+        };
+        let template = Template::parse("Lists/{sender_domain}/{list_id}").unwrap();
+        assert_eq!(
+            template.render(&fields, Some('/')),
+            "Lists/a_b.c_d_e__f/dev.lists.example.org"
+        );
+        assert_eq!(
+            template.render(&fields, Some('.')),
+            "Lists/a_b_c_d_e__f/dev_lists_example_org"
+        );
+        let fields = Fields {
+            sender_domain: None,
+            ..fields
+        };
+        let template = Template::parse("Senders/{sender_domain}").unwrap();
+        assert_eq!(template.render(&fields, None), "Senders/unknown");
+    }
+
+    #[test]
+    fn static_prefix() {
+        let prefix = |text: &str| Template::parse(text).unwrap().static_prefix().to_string();
+        assert_eq!(prefix("Archives/{year}"), "Archives");
+        assert_eq!(prefix("Old/Mail/{year}/{month:02}"), "Old/Mail");
+        assert_eq!(prefix("Archives/Y{year}"), "Archives");
+        assert_eq!(prefix("{year}"), "");
+        assert_eq!(prefix("Archives/"), "Archives");
+    }
+
+    #[test]
+    fn check_granularity() {
+        let template = Template::parse("Archives/{year}/{month:02}").unwrap();
+        assert!(template.check_granularity(Granularity::Year).is_ok());
+        assert!(template.check_granularity(Granularity::Month).is_ok());
+        assert!(template.check_granularity(Granularity::Week).is_err());
+
+        let separates = |text: &str, granularity: Granularity| {
+            Template::parse(text)
+                .unwrap()
+                .check_granularity(granularity)
+                .is_ok()
+        };
+        for granularity in [
+            Granularity::Year,
+            Granularity::Quarter,
+            Granularity::Month,
+            Granularity::Week,
+        ] {
+            assert!(separates(granularity.default_template(), granularity));
+            assert!(separates(
+                granularity.default_nested_template(),
+                granularity
+            ));
+        }
+        // Periods of different years must not share a folder
+        assert!(!separates("Archives/{month:02}", Granularity::Month));
+        assert!(!separates("Archives/Q{quarter}", Granularity::Quarter));
+        assert!(!separates("Archives/{year}/W{week}", Granularity::Week));
+        assert!(separates("Archives/{year}/{month}", Granularity::Quarter));
+    }
+}
@@ -0,0 +1,228 @@
+use crate::archiver::{ImapSession, Uid};
+use crate::header;
+use anyhow::Result;
+use clap::ValueEnum;
+use serde::Deserialize;
+use std::collections::HashMap;
+
+///
+/// Which message of a thread decides the folder the whole thread goes to
+///
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize, ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum ThreadFolder {
+    /// The last reply
+    Newest,
+    /// The message that started the conversation
+    Oldest,
+}
+
+///
+/// Thread of each message in the selected mailbox, as a number shared by
+/// the messages of one thread
+///
+pub type Threads = HashMap<Uid, usize>;
+
+///
+/// Disjoint sets of messages and the message IDs they refer to
+///
+#[derive(Default)]
+struct DisjointSets {
+    parents: Vec<usize>,
+}
+
+impl DisjointSets {
+    fn add(&mut self) -> usize {
+        self.parents.push(self.parents.len());
+        self.parents.len() - 1
+    }
+
+    fn find(&mut self, mut node: usize) -> usize {
+        while self.parents[node] != node {
+            self.parents[node] = self.parents[self.parents[node]];
+            node = self.parents[node];
+        }
+        node
+    }
+
+    fn union(&mut self, a: usize, b: usize) {
+        let (a, b) = (self.find(a), self.find(b));
+        self.parents[a] = b;
+    }
+}
+
+/// FETCH items with the headers that link the messages of a thread
+pub const FETCH_ITEMS: &str = "(UID BODY.PEEK[HEADER.FIELDS (MESSAGE-ID IN-REPLY-TO REFERENCES)])";
+
+///
+/// Groups the messages of the selected mailbox into threads like the
+/// REFERENCES algorithm of RFC 5256 does: messages linked by their
+/// Message-ID, In-Reply-To and References headers, directly or through a
+/// common ancestor that is not in the mailbox, form one thread. The THREAD
+/// command itself cannot be used, as the imap crate fails on its response
+/// and the connection is out of step afterwards.
+///
+/// Every message of the mailbox has to be looked at, as any of them can
+/// link two others, so the headers are fetched in batches.
+///
+#[derive(Default)]
+pub struct ThreadFinder {
+    sets: DisjointSets,
+    ids: HashMap<String, usize>,
+    nodes: Vec<(Uid, usize)>,
+}
+
+impl ThreadFinder {
+    ///
+    /// Fetch the headers of the messages in `uidset` and link them to the
+    /// messages seen so far
+    ///
+    pub fn fetch(&mut self, session: &mut ImapSession, uidset: &str) -> Result<()> {
+        let messages = session.uid_fetch(uidset, FETCH_ITEMS)?;
+        for message in messages.iter() {
+            let Some(uid) = message.uid else {
+                continue;
+            };
+            let node = self.sets.add();
+            let headers = header::unfold(message);
+            for name in ["Message-ID", "In-Reply-To", "References"] {
+                for value in header::values(&headers, name) {
+                    for id in message_ids(value) {
+                        let id_node = *self
+                            .ids
+                            .entry(id.to_string())
+                            .or_insert_with(|| self.sets.add());
+                        self.sets.union(node, id_node);
+                    }
+                }
+            }
+            self.nodes.push((uid, node));
+        }
+        Ok(())
+    }
+
+    ///
+    /// The thread of every message fetched
+    ///
+    pub fn finish(self) -> Threads {
+        let ThreadFinder {
+            mut sets, nodes, ..
+        } = self;
+        nodes
+            .into_iter()
+            .map(|(uid, node)| (uid, sets.find(node)))
+            .collect()
+    }
+}
+
+///
+/// The `<...>` message IDs in a header value
+///
+fn message_ids(value: &str) -> impl Iterator<Item = &str> {
+    value
+        .split('<')
+        .skip(1)
+        .filter_map(|part| part.split_once('>'))
+        .map(|(id, _)| id.trim())
+        .filter(|id| !id.is_empty())
+}
+
+///
+/// The messages of every thread any of `uids` belongs to, one thread after
+/// the other. Messages missing from `threads`, e.g. because they arrived
+/// after the threads were looked up, become threads of their own.
+///
+pub fn expand(uids: &[Uid], threads: &mut Threads) -> Vec<Uid> {
+    let mut members = HashMap::<usize, Vec<Uid>>::new();
+    for (&uid, &thread) in threads.iter() {
+        members.entry(thread).or_default().push(uid);
+    }
+    let mut next_thread = threads.values().max().map_or(0, |thread| thread + 1);
+
+    let mut expanded = Vec::new();
+    for &uid in uids {
+        match threads.get(&uid) {
+            Some(thread) => {
+                // Taken out so that a thread is only added once
+                if let Some(mut thread_uids) = members.remove(thread) {
+                    thread_uids.sort_unstable();
+                    expanded.extend(thread_uids);
+                }
+            }
+            None => {
+                threads.insert(uid, next_thread);
+                next_thread += 1;
+                expanded.push(uid);
+            }
+        }
+    }
+    expanded
+}
+
+///
+/// Move the end of a batch of `length` messages out of `uids` to the
+/// boundary of a thread, backwards if possible and forwards if the first
+/// thread is longer than the batch. The batch then holds more than `length`
+/// messages, and the commands about them have to be split.
+///
+pub fn batch_end(uids: &[Uid], length: usize, threads: &Threads) -> usize {
+    let same_thread = |i: usize| threads.get(&uids[i - 1]) == threads.get(&uids[i]);
+    let length = length.min(uids.len());
+    let mut end = length;
+    while end > 0 && end < uids.len() && same_thread(end) {
+        end -= 1;
+    }
+    if end > 0 {
+        return end;
+    }
+    end = length.max(1);
+    while end < uids.len() && same_thread(end) {
+        end += 1;
+    }
+    end
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn threads(groups: &[&[Uid]]) -> Threads {
+        groups
+            .iter()
+            .enumerate()
+            .flat_map(|(thread, uids)| uids.iter().map(move |&uid| (uid, thread)))
+            .collect()
+    }
+
+    #[test]
+    fn message_ids_in_values() {
+        let ids: Vec<&str> = message_ids("<a@x> junk < b@y >, <>, <c@z").collect();
+        assert_eq!(ids, ["a@x", "b@y"]);
+    }
+
+    #[test]
+    fn expand_adds_whole_threads() {
+        let mut found = threads(&[&[1, 5, 9], &[2, 3], &[4]]);
+        let expanded = expand(&[9, 3, 5, 10], &mut found);
+        // Thread of 9, thread of 3, then 10 which arrived meanwhile
+        assert_eq!(expanded, [1, 5, 9, 2, 3, 10]);
+        assert_ne!(found[&10], found[&4]);
+        assert_ne!(found[&10], found[&1]);
+    }
+
+    #[test]
+    fn batch_end_at_thread_boundaries() {
+        let found = threads(&[&[1, 2, 3], &[4, 5], &[6, 7, 8, 9, 10]]);
+        let uids: Vec<Uid> = (1..=10).collect();
+        // Back to the end of the thread the batch would cut
+        assert_eq!(batch_end(&uids, 4, &found), 3);
+        assert_eq!(batch_end(&uids, 5, &found), 5);
+        assert_eq!(batch_end(&uids, 7, &found), 5);
+        // Forward when the first thread is longer than the batch
+        assert_eq!(batch_end(&uids, 1, &found), 3);
+        assert_eq!(batch_end(&uids[5..], 2, &found), 5);
+        // Never past the end
+        assert_eq!(batch_end(&uids, 10, &found), 10);
+        assert_eq!(batch_end(&uids, 20, &found), 10);
+    }
+}